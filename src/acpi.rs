@@ -0,0 +1,273 @@
+use core::{fmt, mem, slice};
+
+use spin::Once;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+
+pub use fadt::Fadt;
+pub use hpet::HpetTable;
+pub use madt::Madt;
+
+/// Why the ACPI tables could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No valid RSDP in the BIOS areas, so the firmware has no ACPI support.
+    NoRsdp,
+    /// The bytes of the table with the given signature do not sum up to zero.
+    BadChecksum([u8; 4]),
+    /// The root table lists no table with the given signature.
+    TableNotFound([u8; 4]),
+    /// The table with the given signature is shorter than its fixed fields.
+    TooShort([u8; 4]),
+    /// The table with the given signature places its registers at address
+    /// zero or in an address space they can not be accessed through.
+    UnsupportedAddress([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn name(signature: &[u8; 4]) -> &str {
+            core::str::from_utf8(signature).unwrap_or("????")
+        }
+        match self {
+            AcpiError::NoRsdp => write!(f, "no RSDP found"),
+            AcpiError::BadChecksum(signature) => write!(f, "bad checksum in {}", name(signature)),
+            AcpiError::TableNotFound(signature) => write!(f, "no {} table", name(signature)),
+            AcpiError::TooShort(signature) => write!(f, "{} table is too short", name(signature)),
+            AcpiError::UnsupportedAddress(signature) => {
+                write!(f, "{} table has unsupported register addresses", name(signature))
+            }
+        }
+    }
+}
+
+/// The Root System Description Pointer, up to the fields of ACPI 2.0.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // the fields below only exist from revision 2 on
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// the size of the revision 0 part of the RSDP
+const RSDP_V1_SIZE: usize = 20;
+
+/// The header every system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The root of the ACPI tables, the RSDT or on newer firmware the XSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTables {
+    /// The revision of the RSDP, 0 for ACPI 1.0 and 2 for everything later.
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    root: PhysAddr,
+    // whether the root holds 64 bit pointers
+    extended: bool,
+}
+
+impl AcpiTables {
+    /// Locate the RSDP and validate the root table.
+    ///
+    /// Panics if `init_kernel_memory` has not been called yet, since the
+    /// tables are read through the physical memory mapping.
+    pub fn find() -> Result<AcpiTables, AcpiError> {
+        let rsdp = find_rsdp().ok_or(AcpiError::NoRsdp)?;
+        let rsdp = unsafe { phys_ptr::<Rsdp>(rsdp).read_unaligned() };
+        let tables = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+            AcpiTables { revision: rsdp.revision, oem_id: rsdp.oem_id, root: PhysAddr::new(rsdp.xsdt_address), extended: true }
+        } else {
+            AcpiTables { revision: rsdp.revision, oem_id: rsdp.oem_id, root: PhysAddr::new(rsdp.rsdt_address as u64), extended: false }
+        };
+        let signature = if tables.extended { *b"XSDT" } else { *b"RSDT" };
+        let header = unsafe { validate(tables.root)? };
+        if header.signature != signature {
+            return Err(AcpiError::TableNotFound(signature));
+        }
+        Ok(tables)
+    }
+
+    /// The physical addresses of all tables listed in the root table.
+    pub fn table_addresses(&self) -> impl Iterator<Item = PhysAddr> {
+        let header = unsafe { phys_ptr::<SdtHeader>(self.root).read_unaligned() };
+        let entry_size = if self.extended { 8 } else { 4 };
+        let count = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+        let entries = self.root + mem::size_of::<SdtHeader>();
+        let extended = self.extended;
+        (0..count).map(move |i| {
+            let entry = entries + (i * entry_size) as u64;
+            let addr = unsafe {
+                if extended {
+                    phys_ptr::<u64>(entry).read_unaligned()
+                } else {
+                    phys_ptr::<u32>(entry).read_unaligned() as u64
+                }
+            };
+            PhysAddr::new(addr)
+        })
+    }
+
+    /// The physical address of the first table with `signature`, after
+    /// checking its checksum.
+    pub fn find_table(&self, signature: [u8; 4]) -> Result<PhysAddr, AcpiError> {
+        let addr = self.table_addresses()
+            .find(|&addr| unsafe { phys_ptr::<SdtHeader>(addr).read_unaligned() }.signature == signature)
+            .ok_or(AcpiError::TableNotFound(signature))?;
+        unsafe { validate(addr)? };
+        Ok(addr)
+    }
+
+    /// Parse the MADT, which lists the interrupt controllers and processors.
+    pub fn madt(&self) -> Result<Madt, AcpiError> {
+        let addr = self.find_table(*b"APIC")?;
+        unsafe { Madt::parse(addr) }
+    }
+
+    /// Parse the FADT, which describes the power management hardware.
+    pub fn fadt(&self) -> Result<Fadt, AcpiError> {
+        let addr = self.find_table(*b"FACP")?;
+        unsafe { Fadt::parse(addr) }
+    }
+
+    /// Parse the HPET table, if the platform has an HPET.
+    pub fn hpet(&self) -> Result<Option<HpetTable>, AcpiError> {
+        match self.find_table(*b"HPET") {
+            Ok(addr) => unsafe { HpetTable::parse(addr) }.map(Some),
+            Err(AcpiError::TableNotFound(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Everything the kernel uses from the ACPI tables, parsed once by `init`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AcpiInfo {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Madt,
+    pub fadt: Fadt,
+    pub hpet: Option<HpetTable>,
+}
+
+impl fmt::Display for AcpiInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let oem_id = core::str::from_utf8(&self.oem_id).unwrap_or("?");
+        write!(f, "ACPI revision {} ({}): {} processors, {} I/O APICs",
+            self.revision, oem_id.trim_end(), self.madt.processors.len(), self.madt.io_apics.len())?;
+        if let Some(port) = self.fadt.pm_timer_block.and_then(|block| block.io_port()) {
+            write!(f, ", PM timer at port {:#x}", port)?;
+        }
+        if let Some(hpet) = &self.hpet {
+            write!(f, ", HPET at {:#x}", hpet.base_address.as_u64())?;
+        }
+        Ok(())
+    }
+}
+
+static INFO: Once<AcpiInfo> = Once::new();
+
+/// Parse the ACPI tables, or return the result of the first successful call.
+///
+/// Panics if `init_kernel_memory` has not been called yet. Needs the heap.
+pub fn init() -> Result<&'static AcpiInfo, AcpiError> {
+    if let Some(info) = INFO.get() {
+        return Ok(info);
+    }
+    let tables = AcpiTables::find()?;
+    let info = AcpiInfo {
+        revision: tables.revision,
+        oem_id: tables.oem_id,
+        madt: tables.madt()?,
+        fadt: tables.fadt()?,
+        hpet: tables.hpet()?,
+    };
+    Ok(INFO.call_once(|| info))
+}
+
+/// The parsed ACPI tables, if `init` succeeded.
+pub fn info() -> Option<&'static AcpiInfo> {
+    INFO.get()
+}
+
+// read once, so that the tables can be parsed without taking the kernel
+// memory lock for every field
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The virtual address of `addr` in the physical memory mapping.
+fn phys_ptr<T>(addr: PhysAddr) -> *const T {
+    let offset = *PHYSICAL_MEMORY_OFFSET.call_once(memory::physical_memory_offset);
+    (offset + addr.as_u64()).as_ptr()
+}
+
+/// The bytes at `addr`, through the physical memory mapping.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// memory is not written while the slice is in use.
+unsafe fn phys_bytes<'a>(addr: PhysAddr, len: usize) -> &'a [u8] {
+    slice::from_raw_parts(phys_ptr::<u8>(addr), len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Read the header of the table at `addr` and check the checksum of the whole table.
+///
+/// This function is unsafe because the caller must guarantee that `addr`
+/// points to a system description table.
+unsafe fn validate(addr: PhysAddr) -> Result<SdtHeader, AcpiError> {
+    let header = phys_ptr::<SdtHeader>(addr).read_unaligned();
+    if (header.length as usize) < mem::size_of::<SdtHeader>() {
+        return Err(AcpiError::TooShort(header.signature));
+    }
+    if !checksum_ok(phys_bytes(addr, header.length as usize)) {
+        return Err(AcpiError::BadChecksum(header.signature));
+    }
+    Ok(header)
+}
+
+/// Search the first KiB of the EBDA and the BIOS area below 1 MiB for the RSDP.
+fn find_rsdp() -> Option<PhysAddr> {
+    // the BIOS data area holds the real mode segment of the EBDA
+    let ebda = unsafe { phys_ptr::<u16>(PhysAddr::new(0x40e)).read_unaligned() } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+    areas.into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| unsafe { is_rsdp(addr) })
+}
+
+unsafe fn is_rsdp(addr: PhysAddr) -> bool {
+    let rsdp = phys_ptr::<Rsdp>(addr).read_unaligned();
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(phys_bytes(addr, RSDP_V1_SIZE)) {
+        return false;
+    }
+    let length = rsdp.length as usize;
+    rsdp.revision < 2 || (length >= mem::size_of::<Rsdp>() && checksum_ok(phys_bytes(addr, length)))
+}
@@ -0,0 +1,168 @@
+use x86_64::PhysAddr;
+
+use super::{phys_bytes, phys_ptr, AcpiError, SdtHeader};
+
+// IAPC_BOOT_ARCH bits
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+// flag bits
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+/// The address space a `GenericAddress` lives in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// The location of a register, the ACPI Generic Address Structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: RegisterSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 0 for undefined, otherwise 1 to 4 for byte to qword accesses.
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    // the 12 byte structure as stored in the tables
+    pub(super) fn parse(bytes: &[u8]) -> Option<GenericAddress> {
+        let bytes = bytes.get(..12)?;
+        let address = u64::from_le_bytes(bytes[4..12].try_into().unwrap());
+        if address == 0 {
+            return None;
+        }
+        let space = match bytes[0] {
+            0 => RegisterSpace::SystemMemory,
+            1 => RegisterSpace::SystemIo,
+            2 => RegisterSpace::PciConfig,
+            other => RegisterSpace::Other(other),
+        };
+        Some(GenericAddress { space, bit_width: bytes[1], bit_offset: bytes[2], access_size: bytes[3], address })
+    }
+
+    // a block of `length` bytes in I/O space, as given by the ACPI 1.0 fields
+    fn io_block(port: u32, length: u8) -> Option<GenericAddress> {
+        (port != 0).then(|| GenericAddress {
+            space: RegisterSpace::SystemIo,
+            bit_width: length.saturating_mul(8),
+            bit_offset: 0,
+            access_size: 0,
+            address: port as u64,
+        })
+    }
+
+    /// The I/O port of a register in I/O space.
+    pub fn io_port(&self) -> Option<u16> {
+        (self.space == RegisterSpace::SystemIo).then_some(self.address as u16)
+    }
+}
+
+/// The Fixed ACPI Description Table, which describes the power management
+/// hardware.
+///
+/// Blocks that are absent are `None`. The 64 bit `X_` fields of newer
+/// revisions take precedence over the 32 bit ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt: PhysAddr,
+    /// The interrupt ACPI events arrive at, as an ISA IRQ.
+    pub sci_interrupt: u16,
+    /// The port `acpi_enable` and `acpi_disable` are written to, 0 if the
+    /// system is always in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: Option<GenericAddress>,
+    pub pm1b_event_block: Option<GenericAddress>,
+    pub pm1a_control_block: Option<GenericAddress>,
+    pub pm1b_control_block: Option<GenericAddress>,
+    pub pm2_control_block: Option<GenericAddress>,
+    /// The ACPI power management timer, running at `PM_TIMER_FREQUENCY`.
+    pub pm_timer_block: Option<GenericAddress>,
+    pub gpe0_block: Option<GenericAddress>,
+    pub gpe1_block: Option<GenericAddress>,
+    /// The CMOS index of the RTC century register, 0 if there is none.
+    pub century_register: u8,
+    pub boot_arch: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// Frequency of the ACPI power management timer in Hz.
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+impl Fadt {
+    /// Parse the FADT at `addr`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `addr`
+    /// points to a valid FADT.
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Result<Fadt, AcpiError> {
+        let header = phys_ptr::<SdtHeader>(addr).read_unaligned();
+        let bytes = phys_bytes(addr, header.length as usize);
+        // everything up to the flags is present since ACPI 1.0
+        if bytes.len() < 116 {
+            return Err(AcpiError::TooShort(header.signature));
+        }
+        let u8_at = |i: usize| bytes.get(i).copied().unwrap_or(0);
+        let u16_at = |i: usize| bytes.get(i..i + 2).map_or(0, |b| u16::from_le_bytes(b.try_into().unwrap()));
+        let u32_at = |i: usize| bytes.get(i..i + 4).map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()));
+        let u64_at = |i: usize| bytes.get(i..i + 8).map_or(0, |b| u64::from_le_bytes(b.try_into().unwrap()));
+        let gas_at = |i: usize| bytes.get(i..).and_then(GenericAddress::parse);
+        // the extended block at `extended` if it is set, the legacy port and length otherwise
+        let block = |extended: usize, port: usize, length: usize| {
+            gas_at(extended).or_else(|| GenericAddress::io_block(u32_at(port), u8_at(length)))
+        };
+
+        let dsdt = match u64_at(140) {
+            0 => u32_at(40) as u64,
+            x_dsdt => x_dsdt,
+        };
+        let flags = u32_at(112);
+        Ok(Fadt {
+            revision: header.revision,
+            dsdt: PhysAddr::new(dsdt),
+            sci_interrupt: u16_at(46),
+            smi_command_port: u32_at(48),
+            acpi_enable: u8_at(52),
+            acpi_disable: u8_at(53),
+            pm1a_event_block: block(148, 56, 88),
+            pm1b_event_block: block(160, 60, 88),
+            pm1a_control_block: block(172, 64, 89),
+            pm1b_control_block: block(184, 68, 89),
+            pm2_control_block: block(196, 72, 90),
+            pm_timer_block: block(208, 76, 91),
+            gpe0_block: block(220, 80, 92),
+            gpe1_block: block(232, 84, 93),
+            century_register: u8_at(108),
+            boot_arch: u16_at(109),
+            flags,
+            reset_register: gas_at(116).filter(|_| flags & FLAG_RESET_REG_SUP != 0),
+            reset_value: u8_at(128),
+        })
+    }
+
+    /// Whether the PM timer counts with 32 instead of 24 bits.
+    pub fn pm_timer_is_32bit(&self) -> bool {
+        self.flags & FLAG_TMR_VAL_EXT != 0
+    }
+
+    /// Whether the legacy ISA devices, like the PIT and the CMOS RTC, exist.
+    ///
+    /// Only meaningful from revision 2 on, older tables always report them.
+    pub fn has_legacy_devices(&self) -> bool {
+        self.revision < 2 || self.boot_arch & BOOT_ARCH_LEGACY_DEVICES != 0
+    }
+
+    /// Whether there is a PS/2 controller.
+    pub fn has_8042(&self) -> bool {
+        self.revision < 2 || self.boot_arch & BOOT_ARCH_8042 != 0
+    }
+}
@@ -0,0 +1,54 @@
+use x86_64::PhysAddr;
+
+use super::{fadt::{GenericAddress, RegisterSpace}, phys_bytes, phys_ptr, AcpiError, SdtHeader};
+
+/// The HPET description table, which tells where the registers of the High
+/// Precision Event Timer are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetTable {
+    pub hardware_revision: u8,
+    /// The number of comparators of the first timer block.
+    pub comparators: u8,
+    /// Whether the main counter has 64 instead of 32 bits.
+    pub counter_64bit: bool,
+    /// Whether the HPET can take over the interrupts of the PIT and the RTC.
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    /// The physical address of the registers.
+    pub base_address: PhysAddr,
+    /// The sequence number of this HPET, for systems with several of them.
+    pub number: u8,
+    /// The smallest number of counter ticks a periodic timer can be set to
+    /// without losing interrupts.
+    pub min_clock_tick: u16,
+}
+
+impl HpetTable {
+    /// Parse the HPET table at `addr`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `addr`
+    /// points to a valid HPET table.
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Result<HpetTable, AcpiError> {
+        let header = phys_ptr::<SdtHeader>(addr).read_unaligned();
+        let bytes = phys_bytes(addr, header.length as usize);
+        if bytes.len() < 56 {
+            return Err(AcpiError::TooShort(header.signature));
+        }
+        let id = u32::from_le_bytes(bytes[36..40].try_into().unwrap());
+        // the registers are always memory mapped
+        let base = match GenericAddress::parse(&bytes[40..52]) {
+            Some(gas) if gas.space == RegisterSpace::SystemMemory => gas.address,
+            _ => return Err(AcpiError::UnsupportedAddress(header.signature)),
+        };
+        Ok(HpetTable {
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            base_address: PhysAddr::new(base),
+            number: bytes[52],
+            min_clock_tick: u16::from_le_bytes([bytes[53], bytes[54]]),
+        })
+    }
+}
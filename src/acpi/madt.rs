@@ -0,0 +1,175 @@
+use core::mem;
+
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use super::{phys_bytes, phys_ptr, AcpiError, SdtHeader};
+
+// entry types
+const PROCESSOR_LOCAL_APIC: u8 = 0;
+const IO_APIC: u8 = 1;
+const INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_NMI: u8 = 4;
+const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Edge,
+    Level,
+}
+
+/// A processor and its local APIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be used, disabled ones must not be started.
+    pub enabled: bool,
+}
+
+/// An I/O APIC and the first global system interrupt it handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not connected to the global system interrupt of the
+/// same number, or not edge triggered and active high.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// A local APIC input the NMI is connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// The processor id, or `0xff` for all processors.
+    pub processor_id: u8,
+    /// `LINT0` or `LINT1`.
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: Trigger,
+}
+
+/// The Multiple APIC Description Table, which lists the processors and
+/// interrupt controllers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    /// The physical address of the local APIC registers of every processor.
+    pub local_apic_address: PhysAddr,
+    /// Whether the legacy 8259 PICs are present as well, and must be masked
+    /// when the APICs are used.
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+// the fixed fields after the header
+#[repr(C, packed)]
+struct MadtFields {
+    local_apic_address: u32,
+    flags: u32,
+}
+
+impl Madt {
+    /// Parse the MADT at `addr`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `addr`
+    /// points to a valid MADT.
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Result<Madt, AcpiError> {
+        let header = phys_ptr::<SdtHeader>(addr).read_unaligned();
+        let fixed = mem::size_of::<SdtHeader>() + mem::size_of::<MadtFields>();
+        if (header.length as usize) < fixed {
+            return Err(AcpiError::TooShort(header.signature));
+        }
+        let fields = phys_ptr::<MadtFields>(addr + mem::size_of::<SdtHeader>()).read_unaligned();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(fields.local_apic_address as u64),
+            has_8259: fields.flags & 1 != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut entries = &phys_bytes(addr, header.length as usize)[fixed..];
+        // every entry starts with its type and length
+        while entries.len() >= 2 {
+            let length = entries[1] as usize;
+            if length < 2 || length > entries.len() {
+                return Err(AcpiError::TooShort(header.signature));
+            }
+            madt.add_entry(entries[0], &entries[..length]);
+            entries = &entries[length..];
+        }
+        Ok(madt)
+    }
+
+    fn add_entry(&mut self, kind: u8, entry: &[u8]) {
+        let u16_at = |i: usize| u16::from_le_bytes([entry[i], entry[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(entry[i..i + 4].try_into().unwrap());
+        match (kind, entry.len()) {
+            (PROCESSOR_LOCAL_APIC, 8..) => self.processors.push(Processor {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: u32_at(4) & 1 != 0,
+            }),
+            (IO_APIC, 12..) => self.io_apics.push(IoApicInfo {
+                id: entry[2],
+                address: PhysAddr::new(u32_at(4) as u64),
+                gsi_base: u32_at(8),
+            }),
+            (INTERRUPT_SOURCE_OVERRIDE, 10..) => {
+                let (polarity, trigger) = decode_flags(u16_at(8));
+                self.overrides.push(InterruptOverride { irq: entry[3], gsi: u32_at(4), polarity, trigger });
+            }
+            (LOCAL_APIC_NMI, 6..) => {
+                let (polarity, trigger) = decode_flags(u16_at(3));
+                self.nmis.push(LocalApicNmi { processor_id: entry[2], lint: entry[5], polarity, trigger });
+            }
+            (LOCAL_APIC_ADDRESS_OVERRIDE, 12..) => {
+                let address = u64::from_le_bytes(entry[4..12].try_into().unwrap());
+                self.local_apic_address = PhysAddr::new(address);
+            }
+            // x2APIC entries and the like are not used yet
+            _ => {}
+        }
+    }
+
+    /// The global system interrupt the ISA IRQ `irq` is connected to, with its
+    /// polarity and trigger mode.
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, Trigger) {
+        self.overrides.iter()
+            .find(|o| o.irq == irq)
+            .map_or((irq as u32, Polarity::ActiveHigh, Trigger::Edge), |o| (o.gsi, o.polarity, o.trigger))
+    }
+}
+
+/// Decode the MPS INTI flags, resolving "conforms to the bus" to the ISA
+/// defaults of edge triggered and active high.
+fn decode_flags(flags: u16) -> (Polarity, Trigger) {
+    let polarity = match flags & 0b11 {
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::ActiveHigh,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b11 => Trigger::Level,
+        _ => Trigger::Edge,
+    };
+    (polarity, trigger)
+}
@@ -1,15 +1,40 @@
-use linked_list_allocator::LockedHeap;
-use x86_64::{structures::paging::{Mapper, Size4KiB, FrameAllocator, mapper::MapToError, Page, PageTableFlags}, VirtAddr};
+use core::{alloc::{GlobalAlloc, Layout}, fmt, ptr::{self, NonNull}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
 
+use x86_64::{structures::paging::{Size2MiB, Size4KiB, mapper::MapToError, Page, PageSize, PageTableFlags}, VirtAddr};
+
+use crate::{backtrace, memory::{self, vmm::{self, Region}, KernelMemory}};
+
+#[cfg(feature = "fixed_size_block")]
+use fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "heap_debug")]
+use debug::DebugHeap;
+
+pub mod debug;
+pub mod fixed_size_block;
+pub mod oom;
+
+pub use oom::{try_box, try_vec_with_capacity, AllocError};
 
 pub const HEAP_START: usize = 0x4242_4242_0000;
-pub const HEAP_SIZE: usize = 1024*1024; // 1MB, increase this if needed
+pub const HEAP_SIZE: usize = 1024*1024; // 1MB initially, grows on demand up to the heap limit
+/// Default ceiling for heap growth, can be changed with `set_heap_limit`.
+pub const HEAP_MAX_SIZE: usize = 64*1024*1024;
+// grow by at least this much, so that many small allocations
+// do not map one page at a time
+const HEAP_GROW_STEP: usize = 64*1024;
 
+const PAGE_SIZE: usize = 4096;
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) ->  Result<(), MapToError<Size4KiB>>{
+// the number of bytes currently mapped for the heap, starting at HEAP_START
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
+
+
+/// Map the initial `HEAP_SIZE` bytes of the heap and hand them to the allocator.
+///
+/// The pages are mapped through the kernel memory set up by
+/// `memory::init_kernel_memory`, which must be called first.
+pub fn init_heap() ->  Result<(), MapToError<Size4KiB>>{
     let heap_start_addr = VirtAddr::new(HEAP_START as u64);
     let heap_end_addr = VirtAddr::new((HEAP_START+HEAP_SIZE-1usize)as u64);
     let start_page = Page::containing_address(heap_start_addr);
@@ -17,16 +42,12 @@ pub fn init_heap(
 
     let page_range = Page::range_inclusive(start_page, end_page);
 
-    for page in page_range {
-        let frame = frame_allocator.allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        let flags = PageTableFlags::WRITABLE | PageTableFlags::PRESENT;
-
-        // map_to function needs a frame_allocator to allocate physical memory
-        // for createing page tables if necessary
-        unsafe {mapper.map_to(page, frame, flags, frame_allocator)?.flush();}
-    }
+    memory::with_kernel_memory(|memory| -> Result<(), MapToError<Size4KiB>> {
+        for page in page_range {
+            map_heap_page(page, memory)?;
+        }
+        Ok(())
+    })?;
 
     // initialize the allocator
     // initialization must happen after mapping all heap pages
@@ -35,12 +56,463 @@ pub fn init_heap(
     unsafe{
         ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
     }
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::Relaxed);
     Ok(())
 }
 
+/// The number of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::Relaxed)
+}
+
+/// The size the heap may grow to.
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+/// Change the size the heap may grow to.
+///
+/// Memory that is already mapped for the heap is never given back, so a
+/// limit below the current `heap_size` only stops further growth. The limit
+/// is capped at the size of the heap region of the kernel address space.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(Region::Heap.size() as usize), Ordering::Relaxed);
+}
+
+fn map_heap_page(page: Page, memory: &mut KernelMemory) -> Result<(), MapToError<Size4KiB>> {
+    vmm::map_page(memory, page, PageTableFlags::WRITABLE)
+}
+
+/// Why `grow_heap` could not add memory to the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GrowError {
+    /// `init_heap` has not been called yet.
+    NotInitialized,
+    /// Growing by the requested size would exceed the heap limit.
+    LimitReached,
+    /// The kernel memory is locked by the code that is allocating.
+    KernelMemoryBusy,
+    /// The frame allocator has no frames left to map.
+    OutOfFrames,
+}
+
+/// Map at least `min_size` more bytes directly above the current heap end.
+///
+/// Returns the number of bytes that were added, which may be less than
+/// `min_size` if the frame allocator runs dry.
+///
+/// Runs with the heap locked, so the kernel memory is only try-locked, see
+/// the lock order documented at `KernelMemory`.
+fn grow_heap(min_size: usize) -> Result<usize, GrowError> {
+    let mapped = HEAP_MAPPED.load(Ordering::Relaxed);
+    // `init_heap` sets up the heap only after the kernel memory
+    if mapped == 0 {
+        return Err(GrowError::NotInitialized);
+    }
+    let available = heap_limit().saturating_sub(mapped);
+    let size = align_up(min_size.max(HEAP_GROW_STEP), PAGE_SIZE).min(available);
+    if size < min_size {
+        return Err(GrowError::LimitReached);
+    }
+
+    let grown = memory::try_with_kernel_memory(|memory| {
+        let mut grown = 0;
+        while grown < size {
+            let addr = VirtAddr::new((HEAP_START + mapped + grown) as u64);
+            // large growth steps use 2MiB pages where the heap end is aligned,
+            // falling back to 4KiB pages if no contiguous block is left
+            if addr.is_aligned(Size2MiB::SIZE) && size - grown >= Size2MiB::SIZE as usize
+                && vmm::map_page(memory, Page::<Size2MiB>::containing_address(addr), PageTableFlags::WRITABLE).is_ok()
+            {
+                grown += Size2MiB::SIZE as usize;
+                continue;
+            }
+            if map_heap_page(Page::containing_address(addr), memory).is_err() {
+                break;
+            }
+            grown += PAGE_SIZE;
+        }
+        grown
+    }).ok_or(GrowError::KernelMemoryBusy)?;
+
+    HEAP_MAPPED.store(mapped + grown, Ordering::Relaxed);
+    match grown {
+        0 => Err(GrowError::OutOfFrames),
+        grown => Ok(grown),
+    }
+}
+
+/// Align the given address `addr` upwards to alignment `align`.
+///
+/// Requires that `align` is a power of two.
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// A heap implementation that can be used as the global allocator.
+///
+/// `Locked` implements `GlobalAlloc` on top of this trait and takes care
+/// of growing the heap when an allocation can not be satisfied.
+pub trait HeapBackend {
+    /// Allocate a block for `layout`, returning a null pointer on failure.
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// Give a block back that was returned by `allocate` with the same `layout`.
+    ///
+    /// This function is unsafe because the caller must guarantee that `ptr`
+    /// is not used after the call.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// Add `by` bytes directly above the current heap end.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// memory is mapped and unused.
+    unsafe fn extend(&mut self, by: usize);
+
+    /// The size of the largest block that could be allocated right now.
+    fn largest_free_block(&mut self) -> usize;
+
+    /// Bytes an allocation of `layout` takes up beyond `layout.size()` and
+    /// its alignment, e.g. for bookkeeping. Used to size heap growth.
+    fn overhead(&self, _layout: Layout) -> usize {
+        0
+    }
+
+    /// Take the corruption the last `deallocate` found, if any.
+    ///
+    /// `Locked` reports it once the heap lock is released, so that the
+    /// report can allocate.
+    fn take_corruption(&mut self) -> Option<debug::Corruption> {
+        None
+    }
+}
+
+impl HeapBackend for linked_list_allocator::Heap {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match self.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        linked_list_allocator::Heap::deallocate(self, NonNull::new_unchecked(ptr), layout);
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        linked_list_allocator::Heap::extend(self, by);
+    }
+
+    fn largest_free_block(&mut self) -> usize {
+        largest_first_fit(self)
+    }
+}
+
+/// Find the largest block a linked list heap can hand out.
+///
+/// The hole list is private to `linked_list_allocator`, so this probes the
+/// heap with a binary search over the allocation size. Every successful probe
+/// is freed again right away.
+pub(crate) fn largest_first_fit(heap: &mut linked_list_allocator::Heap) -> usize {
+    let (mut low, mut high) = (0, heap.free());
+    while low < high {
+        let size = low + (high - low + 1) / 2;
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        match heap.allocate_first_fit(layout) {
+            Ok(ptr) => {
+                unsafe { heap.deallocate(ptr, layout) };
+                low = size;
+            }
+            Err(_) => high = size - 1,
+        }
+    }
+    low
+}
+
+unsafe impl<B: HeapBackend> GlobalAlloc for Locked<B> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut heap = self.lock();
+        let mut ptr = heap.allocate(layout);
+        if ptr.is_null() {
+            // the heap is exhausted, map more pages and try once more
+            // the extra `align` bytes leave room to align the block in the new area
+            let min_size = layout.size() + layout.align() + heap.overhead(layout);
+            let mut grown = grow_heap(min_size);
+            // if there are no frames left to map, empty the caches first, but
+            // not when the heap limit is what stops the growth. The cached
+            // objects may free heap memory when dropped, so the heap is
+            // unlocked meanwhile.
+            if let Err(GrowError::OutOfFrames) = grown {
+                drop(heap);
+                let reclaimed = oom::reclaim_memory();
+                heap = self.lock();
+                ptr = heap.allocate(layout);
+                if ptr.is_null() && reclaimed > 0 {
+                    grown = grow_heap(min_size);
+                }
+            }
+            match grown {
+                Ok(grown) if ptr.is_null() => {
+                    heap.extend(grown);
+                    ptr = heap.allocate(layout);
+                }
+                _ => {}
+            }
+        }
+
+        if !ptr.is_null() {
+            record_alloc(ptr, layout);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let corruption = {
+            let mut heap = self.lock();
+            heap.deallocate(ptr, layout);
+            // still under the heap lock, so the address can not be handed
+            // out and recorded again before it is removed
+            record_dealloc(ptr, layout);
+            heap.take_corruption()
+        };
+        if let Some(corruption) = corruption {
+            debug::report(&corruption);
+        }
+    }
+}
+
+static BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK_BYTES_IN_USE: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+
+/// A snapshot of the kernel heap usage, see `stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Bytes handed out and not freed yet, as requested by the callers.
+    pub bytes_in_use: usize,
+    /// The highest `bytes_in_use` seen since boot.
+    pub peak_bytes_in_use: usize,
+    /// Number of successful allocations since boot.
+    pub allocations: usize,
+    /// Number of frees since boot.
+    pub frees: usize,
+    /// Bytes currently mapped for the heap.
+    pub heap_size: usize,
+    /// The largest allocation that would succeed without growing the heap.
+    pub largest_free_block: usize,
+}
+
+/// Return the current heap statistics.
+pub fn stats() -> HeapStats {
+    // take the heap lock first, so the counters match the heap state
+    let mut heap = ALLOCATOR.lock();
+    HeapStats {
+        bytes_in_use: BYTES_IN_USE.load(Ordering::Relaxed),
+        peak_bytes_in_use: PEAK_BYTES_IN_USE.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        heap_size: heap_size(),
+        largest_free_block: heap.largest_free_block(),
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes in use (peak {}), {} allocations, {} frees, heap size {}, largest free block {}",
+            self.bytes_in_use, self.peak_bytes_in_use, self.allocations, self.frees,
+            self.heap_size, self.largest_free_block)
+    }
+}
+
+// never inlined, so that the number of frames to skip is fixed
+#[inline(never)]
+fn record_alloc(ptr: *mut u8, layout: Layout) {
+    let in_use = BYTES_IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+    PEAK_BYTES_IN_USE.fetch_max(in_use, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+
+    if TRACKING.load(Ordering::Relaxed) {
+        // skip `record_alloc`, `Locked::alloc` and the `__rust_alloc` shim
+        let caller = backtrace::capture(3);
+        LIVE_ALLOCATIONS.lock().insert(Allocation { address: ptr as usize, size: layout.size(), caller });
+    }
+}
+
+fn record_dealloc(ptr: *mut u8, layout: Layout) {
+    BYTES_IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+    FREES.fetch_add(1, Ordering::Relaxed);
+
+    if TRACKING.load(Ordering::Relaxed) {
+        LIVE_ALLOCATIONS.lock().remove(ptr as usize, layout.size());
+    }
+}
+
+/// Number of return addresses recorded for each tracked allocation.
+pub const CALLER_DEPTH: usize = 6;
+/// Number of live allocations that can be tracked at the same time.
+pub const MAX_TRACKED_ALLOCATIONS: usize = 256;
+
+/// An allocation recorded by `track_allocations`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    pub address: usize,
+    pub size: usize,
+    /// Return addresses of the allocating call stack, innermost first.
+    pub caller: [usize; CALLER_DEPTH],
+}
+
+impl fmt::Debug for Allocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes at {:#x}, allocated from", self.size, self.address)?;
+        for address in self.caller.iter().take_while(|&&a| a != 0) {
+            write!(f, " {:#x}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// The allocations made while tracking was enabled that are still alive.
+///
+/// The storage lives outside the heap, so the table is fixed in size.
+/// Live allocations that did not fit into it are only counted, per power
+/// of two size class. Freeing an allocation made before tracking started
+/// can therefore only be mistaken for an untracked one of the same class.
+pub struct LiveAllocations {
+    entries: [Option<Allocation>; MAX_TRACKED_ALLOCATIONS],
+    // indexed by `size_class`
+    untracked: [usize; usize::BITS as usize + 1],
+}
+
+/// The number of bits needed to store `size`.
+fn size_class(size: usize) -> usize {
+    (usize::BITS - size.leading_zeros()) as usize
+}
+
+impl LiveAllocations {
+    const fn new() -> Self {
+        LiveAllocations { entries: [None; MAX_TRACKED_ALLOCATIONS], untracked: [0; usize::BITS as usize + 1] }
+    }
+
+    fn insert(&mut self, allocation: Allocation) {
+        match self.entries.iter_mut().find(|e| e.is_none()) {
+            Some(entry) => *entry = Some(allocation),
+            None => self.untracked[size_class(allocation.size)] += 1,
+        }
+    }
+
+    fn remove(&mut self, address: usize, size: usize) {
+        match self.entries.iter_mut().find(|e| matches!(e, Some(a) if a.address == address)) {
+            Some(entry) => *entry = None,
+            // either allocated before tracking started or never fit into the table
+            None => {
+                let untracked = &mut self.untracked[size_class(size)];
+                *untracked = untracked.saturating_sub(1);
+            }
+        }
+    }
+
+    /// The number of leaked allocations whose details were not recorded.
+    pub fn untracked(&self) -> usize {
+        self.untracked.iter().sum()
+    }
+
+    /// The number of leaked allocations, tracked or not.
+    pub fn count(&self) -> usize {
+        self.iter().count() + self.untracked()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    /// The leaked allocations whose details were recorded.
+    pub fn iter(&self) -> impl Iterator<Item = &Allocation> {
+        self.entries.iter().flatten()
+    }
+}
+
+impl fmt::Debug for LiveAllocations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LiveAllocations")
+            .field("allocations", &DebugList(self))
+            .field("untracked", &self.untracked())
+            .finish()
+    }
+}
+
+struct DebugList<'a>(&'a LiveAllocations);
+
+impl fmt::Debug for DebugList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.0.iter()).finish()
+    }
+}
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static LIVE_ALLOCATIONS: spin::Mutex<LiveAllocations> = spin::Mutex::new(LiveAllocations::new());
+
+/// Run `f` and report every allocation it made that is still alive afterwards.
+///
+/// Allocations from interrupt handlers that run meanwhile are recorded too.
+/// Tracking can not be nested.
+///
+/// ```ignore
+/// let (_, leaks) = allocator::track_allocations(|| { let _ = Box::new(1); });
+/// assert!(leaks.is_empty(), "{:?}", leaks);
+/// ```
+pub fn track_allocations<R>(f: impl FnOnce() -> R) -> (R, LiveAllocations) {
+    assert!(!TRACKING.swap(true, Ordering::Relaxed), "allocation tracking is already enabled");
+    *LIVE_ALLOCATIONS.lock() = LiveAllocations::new();
+
+    let result = f();
+
+    TRACKING.store(false, Ordering::Relaxed);
+    let leaks = core::mem::replace(&mut *LIVE_ALLOCATIONS.lock(), LiveAllocations::new());
+    (result, leaks)
+}
+
 // A allocator is used to allocate memory(Both virtual memory and physical memory) for 
 // objects during runtime
-#[global_allocator]
+// enable the `fixed_size_block` feature to replace the linked list heap with
+// per-size-class free lists
+#[cfg(feature = "fixed_size_block")]
+type Backend = FixedSizeBlockAllocator;
+#[cfg(feature = "fixed_size_block")]
+const EMPTY_BACKEND: Backend = FixedSizeBlockAllocator::new();
+
 // must initialize allocator after this call
 // empty() does not initialize the allocator with any necessary information
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
\ No newline at end of file
+#[cfg(not(feature = "fixed_size_block"))]
+type Backend = linked_list_allocator::Heap;
+#[cfg(not(feature = "fixed_size_block"))]
+const EMPTY_BACKEND: Backend = linked_list_allocator::Heap::empty();
+
+// enable the `heap_debug` feature to check every allocation for corruption
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static ALLOCATOR: Locked<DebugHeap<Backend>> = Locked::new(DebugHeap::new(EMPTY_BACKEND));
+
+#[cfg(not(feature = "heap_debug"))]
+#[global_allocator]
+static ALLOCATOR: Locked<Backend> = Locked::new(EMPTY_BACKEND);
+
+/// A wrapper around spin::Mutex to permit trait implementations.
+///
+/// `GlobalAlloc` can not be implemented for `spin::Mutex<A>` directly, for
+/// neither the trait nor the type is defined in this crate.
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
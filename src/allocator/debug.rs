@@ -0,0 +1,226 @@
+use core::{alloc::Layout, fmt, mem, ops::{Deref, DerefMut}};
+
+use super::{HeapBackend, CALLER_DEPTH};
+use crate::{backtrace, println, serial_println};
+
+/// Byte written over freed memory, so that use after free shows up as `0x6b6b...`.
+pub const POISON: u8 = 0x6b;
+/// Byte the red zones around every allocation are filled with.
+pub const CANARY: u8 = 0xfd;
+/// Size of the red zone on either side of an allocation.
+pub const RED_ZONE: usize = 16;
+
+const ALIVE: u64 = 0xa110_c8ed_a110_c8ed;
+const FREED: u64 = 0xdead_f7ee_dead_f7ee;
+// left untouched at the start of every block, the backends write their free
+// list nodes there, so the header of a freed block survives until it is reused
+const SLACK: usize = 16;
+
+/// Bookkeeping stored in front of the red zone before every allocation.
+#[repr(C)]
+struct Header {
+    magic: u64,
+    size: usize,
+    // distance from the start of the backend block to the allocation
+    offset: usize,
+    caller: [usize; CALLER_DEPTH],
+}
+
+/// What `check_allocation` found wrong with an allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// Something wrote below the start of the allocation.
+    FrontRedZone,
+    /// Something wrote past the end of the allocation.
+    BackRedZone,
+    /// The allocation was freed already.
+    DoubleFree,
+    /// The header is gone, so the pointer was never allocated or the header
+    /// was overwritten.
+    BadHeader,
+}
+
+/// A corrupted allocation, with where it was allocated if that is still known.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    pub kind: CorruptionKind,
+    pub address: usize,
+    pub size: usize,
+    /// Return addresses of the allocating call stack, innermost first.
+    pub caller: [usize; CALLER_DEPTH],
+}
+
+impl fmt::Debug for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} for {} bytes at {:#x}, allocated from", self.kind, self.size, self.address)?;
+        for address in self.caller.iter().take_while(|&&a| a != 0) {
+            write!(f, " {:#x}", address)?;
+        }
+        Ok(())
+    }
+}
+
+/// A heap backend wrapper that catches heap corruption, enabled with the
+/// `heap_debug` feature.
+///
+/// Every allocation is surrounded by `RED_ZONE` bytes of `CANARY`, which
+/// are verified when it is freed, and freed memory is filled with `POISON`.
+/// A smashed canary, a double free or a free of a pointer the heap never
+/// handed out is reported together with the call site of the allocation,
+/// and then panics. Double frees are only caught until the block is reused.
+pub struct DebugHeap<B> {
+    inner: B,
+    // found by `deallocate`, reported by `Locked` once the heap is unlocked
+    corruption: Option<Corruption>,
+}
+
+impl<B> DebugHeap<B> {
+    pub const fn new(inner: B) -> Self {
+        DebugHeap { inner, corruption: None }
+    }
+}
+
+// so that backend specific methods like `init` stay reachable
+impl<B> Deref for DebugHeap<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for DebugHeap<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+/// Offset of the allocation from the start of its backend block.
+fn front(layout: Layout) -> usize {
+    let align = layout.align().max(mem::align_of::<Header>());
+    (SLACK + mem::size_of::<Header>() + RED_ZONE + align - 1) & !(align - 1)
+}
+
+fn inner_layout(layout: Layout) -> Layout {
+    let align = layout.align().max(mem::align_of::<Header>());
+    Layout::from_size_align(front(layout) + layout.size() + RED_ZONE, align).unwrap()
+}
+
+unsafe fn header(ptr: *const u8) -> *mut Header {
+    ptr.sub(RED_ZONE + mem::size_of::<Header>()) as *mut Header
+}
+
+unsafe fn is_filled(start: *const u8, len: usize, byte: u8) -> bool {
+    core::slice::from_raw_parts(start, len).iter().all(|&b| b == byte)
+}
+
+/// Verify the header and the red zones of a live allocation made by `DebugHeap`.
+///
+/// This function is unsafe because the caller must guarantee that `ptr` was
+/// returned by a `DebugHeap` and that its block has not been reused since it
+/// was freed.
+pub unsafe fn check_allocation(ptr: *const u8) -> Result<(), Corruption> {
+    let header = &*header(ptr);
+    let mut corruption = Corruption {
+        kind: CorruptionKind::BadHeader,
+        address: ptr as usize,
+        size: 0,
+        caller: [0; CALLER_DEPTH],
+    };
+    match header.magic {
+        ALIVE => {}
+        FREED => corruption.kind = CorruptionKind::DoubleFree,
+        _ => return Err(corruption),
+    }
+    corruption.size = header.size;
+    corruption.caller = header.caller;
+    if corruption.kind == CorruptionKind::DoubleFree {
+        return Err(corruption);
+    }
+
+    if !is_filled(ptr.sub(RED_ZONE), RED_ZONE, CANARY) {
+        corruption.kind = CorruptionKind::FrontRedZone;
+        return Err(corruption);
+    }
+    if !is_filled(ptr.add(header.size), RED_ZONE, CANARY) {
+        corruption.kind = CorruptionKind::BackRedZone;
+        return Err(corruption);
+    }
+    Ok(())
+}
+
+/// Print `corruption` with the current call stack and panic.
+///
+/// Must be called without holding the heap lock, printing and panicking
+/// may allocate.
+pub(super) fn report(corruption: &Corruption) -> ! {
+    println!("HEAP CORRUPTION: {:?}", corruption);
+    serial_println!("HEAP CORRUPTION: {:?}", corruption);
+    backtrace::print();
+    panic!("heap corruption: {:?} at {:#x}", corruption.kind, corruption.address)
+}
+
+impl<B: HeapBackend> HeapBackend for DebugHeap<B> {
+    // never inlined, so that the number of frames to skip is fixed
+    #[inline(never)]
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let block = self.inner.allocate(inner_layout(layout));
+        if block.is_null() {
+            return block;
+        }
+        let offset = front(layout);
+        unsafe {
+            let ptr = block.add(offset);
+            header(ptr).write(Header {
+                magic: ALIVE,
+                size: layout.size(),
+                offset,
+                // skip `DebugHeap::allocate`, `Locked::alloc` and the `__rust_alloc` shim
+                caller: backtrace::capture(3),
+            });
+            ptr.sub(RED_ZONE).write_bytes(CANARY, RED_ZONE);
+            ptr.add(layout.size()).write_bytes(CANARY, RED_ZONE);
+            ptr
+        }
+    }
+
+    // a corrupted block is never freed, so that the heap stays usable
+    // for the report
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        if let Err(corruption) = check_allocation(ptr) {
+            self.corruption = Some(corruption);
+            return;
+        }
+        let header = &mut *header(ptr);
+        if header.size != layout.size() || header.offset != front(layout) {
+            self.corruption = Some(Corruption {
+                kind: CorruptionKind::BadHeader,
+                address: ptr as usize,
+                size: header.size,
+                caller: header.caller,
+            });
+            return;
+        }
+
+        header.magic = FREED;
+        ptr.write_bytes(POISON, layout.size());
+        self.inner.deallocate(ptr.sub(header.offset), inner_layout(layout));
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.inner.extend(by);
+    }
+
+    fn take_corruption(&mut self) -> Option<Corruption> {
+        self.corruption.take()
+    }
+
+    fn largest_free_block(&mut self) -> usize {
+        let overhead = self.overhead(Layout::new::<u8>());
+        self.inner.largest_free_block().saturating_sub(overhead)
+    }
+
+    fn overhead(&self, layout: Layout) -> usize {
+        inner_layout(layout).size() - layout.size()
+    }
+}
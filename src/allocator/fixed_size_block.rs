@@ -0,0 +1,117 @@
+use core::{alloc::Layout, mem, ptr::{self, NonNull}};
+
+use super::{largest_first_fit, HeapBackend};
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Header of a free block, written into the block itself.
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// Allocator with one free list per size class.
+///
+/// Requests that fit a size class are served from its list in O(1); larger
+/// requests, and the initial carving of new blocks, go to a linked list heap.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: linked_list_allocator::Heap,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// This function is unsafe because the caller must guarantee that the given
+    /// heap bounds are valid and that the heap is unused. This method must be
+    /// called only once.
+    pub unsafe fn init(&mut self, heap_start: *mut u8, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Allocates using the fallback allocator.
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+impl HeapBackend for FixedSizeBlockAllocator {
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => {
+                match self.list_heads[index].take() {
+                    Some(node) => {
+                        self.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        // no block exists in list => allocate new block
+                        let block_size = BLOCK_SIZES[index];
+                        // only works if all block sizes are a power of 2
+                        let block_align = block_size;
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        self.fallback_alloc(layout)
+                    }
+                }
+            }
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                self.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    unsafe fn extend(&mut self, by: usize) {
+        self.fallback_allocator.extend(by);
+    }
+
+    fn largest_free_block(&mut self) -> usize {
+        let largest_block = BLOCK_SIZES.iter()
+            .zip(self.list_heads.iter())
+            .filter(|(_, head)| head.is_some())
+            .map(|(&size, _)| size)
+            .max()
+            .unwrap_or(0);
+        largest_first_fit(&mut self.fallback_allocator).max(largest_block)
+    }
+}
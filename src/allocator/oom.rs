@@ -0,0 +1,101 @@
+use core::{alloc::Layout, fmt};
+
+use alloc::{alloc::alloc, boxed::Box, vec::Vec};
+
+use crate::{backtrace, memory, println, serial_println, slab};
+
+// the number of pages swapped out at once when a fallible allocation fails
+const SWAP_RECLAIM_PAGES: usize = 64;
+
+/// Error returned by the fallible allocation functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The requested size does not fit into `isize`.
+    CapacityOverflow,
+    /// The heap could not provide a block for the layout, even after growing.
+    OutOfMemory(Layout),
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AllocError::CapacityOverflow => write!(f, "capacity overflow"),
+            AllocError::OutOfMemory(layout) => write!(f, "out of memory allocating {:?}", layout),
+        }
+    }
+}
+
+/// Like `Box::new`, but returns an error instead of invoking the OOM handler.
+///
+/// If the heap is out of memory, cold pages are swapped out and the
+/// allocation is tried once more.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        // zero sized values never touch the heap
+        return Ok(Box::new(value));
+    }
+
+    let mut ptr = unsafe { alloc(layout) } as *mut T;
+    if ptr.is_null() && swap_out() {
+        ptr = unsafe { alloc(layout) } as *mut T;
+    }
+    if ptr.is_null() {
+        return Err(AllocError::OutOfMemory(layout));
+    }
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+/// Like `Vec::with_capacity`, but returns an error instead of invoking the OOM handler.
+///
+/// Swaps out cold pages and retries once like `try_box`.
+pub fn try_vec_with_capacity<T>(capacity: usize) -> Result<Vec<T>, AllocError> {
+    let layout = Layout::array::<T>(capacity).map_err(|_| AllocError::CapacityOverflow)?;
+    let mut v = Vec::new();
+    if v.try_reserve_exact(capacity).is_err() && swap_out() {
+        v.try_reserve_exact(capacity).map_err(|_| AllocError::OutOfMemory(layout))?;
+    }
+    if v.capacity() < capacity {
+        return Err(AllocError::OutOfMemory(layout));
+    }
+    Ok(v)
+}
+
+// Swapping writes to a block device, which must not happen inside
+// `GlobalAlloc::alloc` with the heap locked, so only the fallible
+// allocation functions fall back to it, after the heap lock is released.
+fn swap_out() -> bool {
+    memory::swap::reclaim(SWAP_RECLAIM_PAGES) > 0
+}
+
+/// Free memory held by caches, so that the heap has frames to grow into.
+///
+/// Returns the number of frames that were given back to the frame allocator.
+/// The heap calls this without holding its lock, so the destructors of
+/// cached slab objects may free heap memory.
+pub fn reclaim_memory() -> usize {
+    slab::shrink_all()
+}
+
+/// Print everything known about a failed allocation to VGA and serial.
+pub fn report(layout: Layout) {
+    let stats = super::stats();
+    println!("OUT OF MEMORY: allocation of {:?} failed", layout);
+    serial_println!("OUT OF MEMORY: allocation of {:?} failed", layout);
+    println!("Heap: {}", stats);
+    serial_println!("Heap: {}", stats);
+    println!("Heap limit: {} bytes", super::heap_limit());
+    serial_println!("Heap limit: {} bytes", super::heap_limit());
+    backtrace::print();
+}
+
+// called by `alloc` when an infallible allocation returns null,
+// growing the heap and shrinking the caches has already been tried by then
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    report(layout);
+    panic!("allocation error: {:?}", layout)
+}
@@ -0,0 +1,220 @@
+use core::fmt;
+
+use alloc::vec::Vec;
+use spin::{Mutex, Once};
+use x86_64::{registers::model_specific::Msr, PhysAddr};
+
+use crate::{
+    acpi::{madt::{Polarity, Trigger}, AcpiError, Madt},
+    interrupts::{InterruptIndex, PICS},
+    memory::{ioremap, mmio::IoremapError, IoMem},
+};
+
+/// Vector the local APIC raises for spurious interrupts, which need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LAPIC_LVT_ERROR: usize = 0x370;
+const LAPIC_SIZE: usize = 0x400;
+
+const SVR_ENABLE: u32 = 1 << 8;
+
+// bits shared by local vector table and redirection entries
+const DELIVERY_NMI: u32 = 0b100 << 8;
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const MASKED: u32 = 1 << 16;
+
+// I/O APIC registers, accessed through a select and a data window
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_SIZE: usize = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+/// Why the APICs could not be set up.
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPUID reports no local APIC.
+    NotSupported,
+    /// The MADT could not be read.
+    Acpi(AcpiError),
+    /// The MADT lists no I/O APIC.
+    NoIoApic,
+    /// No I/O APIC handles the global system interrupt.
+    NoRoute(u32),
+    /// The registers could not be mapped.
+    Map(IoremapError),
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApicError::NotSupported => write!(f, "no local APIC"),
+            ApicError::Acpi(err) => write!(f, "{}", err),
+            ApicError::NoIoApic => write!(f, "no I/O APIC"),
+            ApicError::NoRoute(gsi) => write!(f, "no I/O APIC for GSI {}", gsi),
+            ApicError::Map(err) => write!(f, "failed to map the registers: {:?}", err),
+        }
+    }
+}
+
+/// The local APIC of the processor, which receives the interrupts routed
+/// by the I/O APICs.
+pub struct LocalApic {
+    regs: IoMem,
+}
+
+impl LocalApic {
+    /// The APIC id of the processor, which I/O APIC entries are addressed to.
+    pub fn id(&self) -> u8 {
+        (self.regs.read::<u32>(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Signal the end of the interrupt being handled.
+    pub fn end_of_interrupt(&self) {
+        self.regs.write::<u32>(LAPIC_EOI, 0);
+    }
+}
+
+/// An I/O APIC, which turns device interrupt lines into messages to the
+/// local APICs.
+pub struct IoApic {
+    regs: IoMem,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, reg: u32) -> u32 {
+        self.regs.write::<u32>(IOAPIC_SELECT, reg);
+        self.regs.read::<u32>(IOAPIC_WINDOW)
+    }
+
+    fn write(&mut self, reg: u32, value: u32) {
+        self.regs.write::<u32>(IOAPIC_SELECT, reg);
+        self.regs.write::<u32>(IOAPIC_WINDOW, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    /// Deliver `gsi` to `vector` on the local APIC with id `destination`.
+    fn route(&mut self, gsi: u32, vector: u8, polarity: Polarity, trigger: Trigger, destination: u8) {
+        let mut low = vector as u32;
+        if polarity == Polarity::ActiveLow {
+            low |= ACTIVE_LOW;
+        }
+        if trigger == Trigger::Level {
+            low |= LEVEL_TRIGGERED;
+        }
+        let reg = IOAPIC_REDIRECTION + 2 * (gsi - self.gsi_base);
+        // mask the entry while it is half written
+        self.write(reg, MASKED);
+        self.write(reg + 1, (destination as u32) << 24);
+        self.write(reg, low);
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// The local APIC, once `init` switched the interrupt controller over to it.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// Switch from the 8259 PICs to the local and I/O APICs found in the MADT.
+///
+/// The ISA IRQs keep their vectors and the PICs are masked. On error the
+/// PICs stay in use. Must be called after `allocator::init_heap`.
+pub fn init() -> Result<(), ApicError> {
+    if LOCAL_APIC.is_completed() {
+        return Ok(());
+    }
+    if crate::cpuid(1).edx & (1 << 9) == 0 {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = &crate::acpi::init().map_err(ApicError::Acpi)?.madt;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let local = unsafe { map_local_apic(madt)? };
+    let mut io_apics = Vec::new();
+    for info in &madt.io_apics {
+        let regs = unsafe { ioremap(info.address, IOAPIC_SIZE) }.map_err(ApicError::Map)?;
+        let mut io_apic = IoApic { regs, gsi_base: info.gsi_base, entries: 0 };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        for entry in 0..io_apic.entries {
+            io_apic.write(IOAPIC_REDIRECTION + 2 * entry, MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+    for index in InterruptIndex::ALL {
+        let (gsi, _, _) = madt.isa_irq(index.irq());
+        if !io_apics.iter().any(|io_apic| io_apic.handles(gsi)) {
+            return Err(ApicError::NoRoute(gsi));
+        }
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        unsafe { PICS.lock().disable() };
+        enable_local_apic(&local, madt);
+        let destination = local.id();
+        for index in InterruptIndex::ALL {
+            let (gsi, polarity, trigger) = madt.isa_irq(index.irq());
+            let io_apic = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)).unwrap();
+            io_apic.route(gsi, index.as_u8(), polarity, trigger, destination);
+        }
+        LOCAL_APIC.call_once(|| local);
+        *IO_APICS.lock() = io_apics;
+    });
+    Ok(())
+}
+
+/// Map the registers of the local APIC and turn it on in the APIC base MSR.
+///
+/// This function is unsafe because it must only be called once.
+unsafe fn map_local_apic(madt: &Madt) -> Result<LocalApic, ApicError> {
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let base = msr.read();
+    // the MSR wins if the firmware moved the registers
+    let address = PhysAddr::new(base & 0x000f_ffff_ffff_f000);
+    let address = if address.is_null() { madt.local_apic_address } else { address };
+    msr.write(base | APIC_BASE_ENABLE);
+    let regs = ioremap(address, LAPIC_SIZE).map_err(ApicError::Map)?;
+    Ok(LocalApic { regs })
+}
+
+fn enable_local_apic(local: &LocalApic, madt: &Madt) {
+    let id = local.id();
+    let processor_id = madt.processors.iter().find(|p| p.apic_id == id).map(|p| p.processor_id);
+    local.regs.write::<u32>(LAPIC_LVT_LINT0, MASKED);
+    local.regs.write::<u32>(LAPIC_LVT_LINT1, MASKED);
+    for nmi in madt.nmis.iter().filter(|nmi| nmi.processor_id == 0xff || Some(nmi.processor_id) == processor_id) {
+        let mut entry = DELIVERY_NMI;
+        if nmi.polarity == Polarity::ActiveLow {
+            entry |= ACTIVE_LOW;
+        }
+        if nmi.trigger == Trigger::Level {
+            entry |= LEVEL_TRIGGERED;
+        }
+        let reg = if nmi.lint == 0 { LAPIC_LVT_LINT0 } else { LAPIC_LVT_LINT1 };
+        local.regs.write::<u32>(reg, entry);
+    }
+    local.regs.write::<u32>(LAPIC_LVT_ERROR, MASKED);
+    // accept interrupts of every priority
+    local.regs.write::<u32>(LAPIC_TPR, 0);
+    local.regs.write::<u32>(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+}
@@ -0,0 +1,73 @@
+use core::arch::asm;
+
+use crate::{println, serial_println};
+
+// stop walking after this many frames, the bootloader does not
+// terminate the frame pointer chain of the kernel entry point
+const MAX_FRAMES: usize = 32;
+
+/// Call `f` with the return address of every frame on the stack, innermost first.
+///
+/// The walk follows the saved frame pointers, so it relies on the target spec
+/// forcing frame pointers (`"frame-pointer": "always"`). It stops early if `f`
+/// returns `false`.
+#[inline(never)]
+pub fn walk(mut f: impl FnMut(usize) -> bool) {
+    let mut rbp: usize;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % core::mem::align_of::<usize>() != 0 {
+            break;
+        }
+        // a frame looks like [saved rbp, return address]
+        let (next, return_address) = unsafe {
+            let frame = rbp as *const usize;
+            (*frame, *frame.add(1))
+        };
+        if return_address == 0 || !f(return_address) {
+            break;
+        }
+        // the stack grows down, so the caller's frame must be above ours
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+/// Capture up to `N` return addresses, skipping the innermost `skip` frames.
+///
+/// Unused slots are left at 0.
+#[inline(never)]
+pub fn capture<const N: usize>(skip: usize) -> [usize; N] {
+    let mut trace = [0; N];
+    let mut index = 0;
+    // one extra frame for `capture` itself
+    let mut skip = skip + 1;
+    walk(|address| {
+        if skip > 0 {
+            skip -= 1;
+            return true;
+        }
+        trace[index] = address;
+        index += 1;
+        index < N
+    });
+    trace
+}
+
+/// Print the return addresses of the current call stack to VGA and serial.
+pub fn print() {
+    println!("Backtrace:");
+    serial_println!("Backtrace:");
+    let mut depth = 0;
+    walk(|address| {
+        println!("  {:>2}: {:#x}", depth, address);
+        serial_println!("  {:>2}: {:#x}", depth, address);
+        depth += 1;
+        true
+    });
+}
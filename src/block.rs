@@ -0,0 +1,87 @@
+use core::fmt;
+
+use alloc::{boxed::Box, vec};
+
+pub mod ata;
+
+/// Size of a block in bytes, the sector size of the devices we support.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Why a block transfer failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The transfer does not fit into the device, or the buffer is not a
+    /// whole number of blocks.
+    OutOfRange,
+    /// The device reported an error, with the contents of its error register.
+    Device(u8),
+    /// The device did not become ready in time.
+    Timeout,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "transfer out of range"),
+            BlockError::Device(error) => write!(f, "device error {:#04x}", error),
+            BlockError::Timeout => write!(f, "device timed out"),
+        }
+    }
+}
+
+/// A device storing data in blocks of `BLOCK_SIZE` bytes.
+///
+/// Transfers are synchronous and may be started with interrupts disabled,
+/// e.g. from the page fault handler, so implementations must neither
+/// allocate nor wait for interrupts.
+pub trait BlockDevice {
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    /// Read `buf.len() / BLOCK_SIZE` blocks starting at block `start`.
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf.len() / BLOCK_SIZE` blocks starting at block `start`.
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), BlockError>;
+}
+
+/// Check that `len` bytes starting at block `start` lie on a device with `blocks` blocks.
+fn check_range(blocks: u64, start: u64, len: usize) -> Result<(), BlockError> {
+    let count = (len / BLOCK_SIZE) as u64;
+    if len % BLOCK_SIZE != 0 || !matches!(start.checked_add(count), Some(end) if end <= blocks) {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// A block device backed by heap memory, for tests and machines without a disk.
+pub struct RamDisk {
+    data: Box<[u8]>,
+}
+
+impl RamDisk {
+    /// Create a zeroed disk of `blocks` blocks.
+    pub fn new(blocks: usize) -> Self {
+        RamDisk { data: vec![0; blocks * BLOCK_SIZE].into_boxed_slice() }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_count(&self) -> u64 {
+        (self.data.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self.block_count(), start, buf.len())?;
+        let offset = start as usize * BLOCK_SIZE;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self.block_count(), start, buf.len())?;
+        let offset = start as usize * BLOCK_SIZE;
+        self.data[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+}
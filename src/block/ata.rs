@@ -0,0 +1,221 @@
+use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
+
+use super::{check_range, BlockDevice, BlockError, BLOCK_SIZE};
+
+// status register bits
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8 = 0xe7;
+const CMD_IDENTIFY: u8 = 0xec;
+
+// status polls before a command is given up
+const TIMEOUT: usize = 1_000_000;
+// 28 bit LBA, so one command moves at most 256 sectors, encoded as 0
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+/// One of the two legacy IDE channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    /// I/O ports `0x1f0..0x1f8`, control port `0x3f6`.
+    Primary,
+    /// I/O ports `0x170..0x178`, control port `0x376`.
+    Secondary,
+}
+
+impl Bus {
+    fn ports(self) -> (u16, u16) {
+        match self {
+            Bus::Primary => (0x1f0, 0x3f6),
+            Bus::Secondary => (0x170, 0x376),
+        }
+    }
+}
+
+/// Which of the two drives of a bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    Master,
+    Slave,
+}
+
+/// An ATA hard disk, driven with polled PIO transfers and 28 bit LBA.
+///
+/// QEMU boots from the primary master, further disks are attached with
+/// e.g. `-drive file=swap.img,format=raw,index=1` for the primary slave.
+/// Only one `AtaDisk` per bus may exist at a time, as both drives share
+/// the same registers.
+pub struct AtaDisk {
+    data: Port<u16>,
+    error: PortReadOnly<u8>,
+    sector_count: PortWriteOnly<u8>,
+    lba_low: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_high: Port<u8>,
+    drive_select: PortWriteOnly<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    alternate_status: PortReadOnly<u8>,
+    drive: Drive,
+    sectors: u64,
+}
+
+impl AtaDisk {
+    /// Identify the disk at `drive` of `bus`.
+    ///
+    /// Returns `None` if there is no drive or it is not an ATA disk, e.g. a
+    /// CD-ROM drive.
+    ///
+    /// This function is unsafe because the caller must guarantee that the
+    /// ports of `bus` are not used by anything else.
+    pub unsafe fn identify(bus: Bus, drive: Drive) -> Option<AtaDisk> {
+        let (base, control) = bus.ports();
+        let mut disk = AtaDisk {
+            data: Port::new(base),
+            error: PortReadOnly::new(base + 1),
+            sector_count: PortWriteOnly::new(base + 2),
+            lba_low: Port::new(base + 3),
+            lba_mid: Port::new(base + 4),
+            lba_high: Port::new(base + 5),
+            drive_select: PortWriteOnly::new(base + 6),
+            status: PortReadOnly::new(base + 7),
+            command: PortWriteOnly::new(base + 7),
+            alternate_status: PortReadOnly::new(control),
+            drive,
+            sectors: 0,
+        };
+
+        disk.select(0xa0);
+        disk.sector_count.write(0);
+        disk.lba_low.write(0);
+        disk.lba_mid.write(0);
+        disk.lba_high.write(0);
+        disk.command.write(CMD_IDENTIFY);
+        // a floating bus reads as 0xff, a missing drive as 0
+        let status = disk.status.read();
+        if status == 0 || status == 0xff {
+            return None;
+        }
+        disk.wait_not_busy().ok()?;
+        // ATAPI and SATA devices put their signature here instead of answering
+        if disk.lba_mid.read() != 0 || disk.lba_high.read() != 0 {
+            return None;
+        }
+        disk.wait_data().ok()?;
+
+        let mut identity = [0u16; 256];
+        for word in identity.iter_mut() {
+            *word = disk.data.read();
+        }
+        // words 60 and 61 hold the number of sectors addressable with 28 bit LBA
+        disk.sectors = identity[60] as u64 | (identity[61] as u64) << 16;
+        Some(disk).filter(|disk| disk.sectors > 0)
+    }
+
+    // write the drive select register and give the drive the 400ns it needs to switch
+    unsafe fn select(&mut self, bits: u8) {
+        let slave = match self.drive {
+            Drive::Master => 0,
+            Drive::Slave => 1 << 4,
+        };
+        self.drive_select.write(bits | slave);
+        for _ in 0..4 {
+            self.alternate_status.read();
+        }
+    }
+
+    unsafe fn wait_not_busy(&mut self) -> Result<u8, BlockError> {
+        for _ in 0..TIMEOUT {
+            let status = self.status.read();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    // wait until the drive is ready to transfer the next sector
+    unsafe fn wait_data(&mut self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device(self.error.read()));
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err(BlockError::Timeout);
+        }
+        Ok(())
+    }
+
+    // wait until the drive finished the current command
+    unsafe fn wait_done(&mut self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device(self.error.read()));
+        }
+        Ok(())
+    }
+
+    unsafe fn start(&mut self, command: u8, lba: u64, sectors: usize) {
+        self.select(0xe0 | ((lba >> 24) & 0x0f) as u8);
+        self.sector_count.write(sectors as u8);
+        self.lba_low.write(lba as u8);
+        self.lba_mid.write((lba >> 8) as u8);
+        self.lba_high.write((lba >> 16) as u8);
+        self.command.write(command);
+    }
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&mut self, start: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self.sectors, start, buf.len())?;
+        let mut lba = start;
+        for chunk in buf.chunks_mut(MAX_SECTORS_PER_COMMAND * BLOCK_SIZE) {
+            unsafe {
+                self.start(CMD_READ_SECTORS, lba, chunk.len() / BLOCK_SIZE);
+                for sector in chunk.chunks_mut(BLOCK_SIZE) {
+                    self.wait_data()?;
+                    for bytes in sector.chunks_mut(2) {
+                        bytes.copy_from_slice(&self.data.read().to_le_bytes());
+                    }
+                }
+            }
+            lba += (chunk.len() / BLOCK_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_range(self.sectors, start, buf.len())?;
+        let mut lba = start;
+        for chunk in buf.chunks(MAX_SECTORS_PER_COMMAND * BLOCK_SIZE) {
+            unsafe {
+                self.start(CMD_WRITE_SECTORS, lba, chunk.len() / BLOCK_SIZE);
+                for sector in chunk.chunks(BLOCK_SIZE) {
+                    self.wait_data()?;
+                    for bytes in sector.chunks(2) {
+                        self.data.write(u16::from_le_bytes([bytes[0], bytes[1]]));
+                    }
+                }
+                // the drive is still busy with the last sector, and a
+                // command sent before it is done would be lost
+                self.wait_done()?;
+            }
+            lba += (chunk.len() / BLOCK_SIZE) as u64;
+        }
+        // the data is only safe once it left the drive's write cache
+        unsafe {
+            self.command.write(CMD_CACHE_FLUSH);
+            self.wait_done()?;
+        }
+        Ok(())
+    }
+}
@@ -1,42 +1,61 @@
+use core::cell::UnsafeCell;
 
 use x86_64::VirtAddr;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{CS, Segment};
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::Size4KiB;
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
 
+use crate::memory::KernelStack;
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Page faults get their own stack, so that a fault on a lazily backed
+/// stack page can be resolved without pushing onto that very page. The
+/// handler must not fault itself, since a nested fault would start on the
+/// same stack; it panics if one does.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+const IST_INDEXES: [u16; 2] = [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX];
+
+/// Size of the stacks `init_stacks` allocates for the IST entries, in pages.
+pub const IST_STACK_PAGES: u64 = 5;
+
+/// The TSS, kept in an `UnsafeCell` so that IST entries can be replaced after it is loaded.
+///
+/// The CPU reads the IST entries only when an interrupt arrives, so writing a
+/// new entry takes effect for the next interrupt that uses it.
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// only written by `init_stacks`, with interrupts disabled
+unsafe impl Sync for Tss {}
+
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
 
 // Those initialization progress is done during runtime,
 // but static variable must be evaluated during compilation time
 // use lazy initialization to save the world
-lazy_static!{
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            // allocation mechanism is not implemented yet
-            // use static mut to simulate the stack
-            // must use mut, otherwise bootloader will allocate this area into read-only page
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // unsafe is necessary, for compiler taking this competable variable is not safe
-            let stack_start = VirtAddr::from_ptr(unsafe {
-                &STACK
-            });
-            let stack_end = stack_start+STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
 lazy_static!{
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        // until `init_stacks` runs, there is no way to map memory,
+        // so the IST handlers start out on static stacks
+        const STACK_SIZE: usize = 4096 * 5;
+        // must use mut, otherwise bootloader will allocate this area into read-only page
+        static mut STACKS: [[u8; STACK_SIZE]; IST_INDEXES.len()] = [[0; STACK_SIZE]; IST_INDEXES.len()];
+
+        for (i, &index) in IST_INDEXES.iter().enumerate() {
+            unsafe {
+                let stack_start = VirtAddr::from_ptr(core::ptr::addr_of!(STACKS[i]));
+                (*TSS.0.get()).interrupt_stack_table[index as usize] = stack_start+STACK_SIZE;
+            }
+        }
+
         let mut gdt = GlobalDescriptorTable::new();
         // kernel_code_segment automaticly get the current running kernel code segment descriptor
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
         (gdt, Selectors{code_selector,tss_selector})
     };
 }
@@ -49,7 +68,27 @@ pub fn init() {
     }
 }
 
+/// Move the IST entries onto runtime allocated stacks with guard pages.
+///
+/// Must be called after `init` and `memory::init_kernel_memory`. Afterwards a
+/// stack overflow inside an IST handler hits a guard page instead of
+/// overwriting the statics next to the boot stacks.
+pub fn init_stacks() -> Result<(), MapToError<Size4KiB>> {
+    for index in IST_INDEXES {
+        let stack = KernelStack::new(IST_STACK_PAGES)?;
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            (*TSS.0.get()).interrupt_stack_table[index as usize] = stack.leak();
+        });
+    }
+    Ok(())
+}
+
+/// The stack pointer loaded for interrupts that use the given IST entry.
+pub fn ist_stack_top(index: u16) -> VirtAddr {
+    unsafe { (*TSS.0.get()).interrupt_stack_table[index as usize] }
+}
+
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
-}
\ No newline at end of file
+}
@@ -0,0 +1,105 @@
+use core::fmt;
+
+use spin::Once;
+
+use crate::{acpi::{self, AcpiError}, memory::{ioremap, mmio::IoremapError, IoMem}};
+
+// registers
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+const REGISTERS_SIZE: usize = 0x400;
+
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+const CONF_ENABLE: u64 = 1 << 0;
+
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+// the longest counter period the specification allows, 100ns
+const MAX_PERIOD_FS: u64 = 0x05f5_e100;
+
+/// Why the HPET could not be set up.
+#[derive(Debug)]
+pub enum HpetError {
+    Acpi(AcpiError),
+    /// The ACPI tables list no HPET.
+    NotPresent,
+    Map(IoremapError),
+    /// The capabilities register reports a counter period of 0 or above
+    /// 100ns, in femtoseconds.
+    InvalidPeriod(u64),
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HpetError::Acpi(err) => write!(f, "{}", err),
+            HpetError::NotPresent => write!(f, "no HPET"),
+            HpetError::Map(err) => write!(f, "failed to map the registers: {:?}", err),
+            HpetError::InvalidPeriod(period) => write!(f, "invalid counter period of {}fs", period),
+        }
+    }
+}
+
+/// The High Precision Event Timer, used as a free running counter.
+///
+/// Its comparators are left alone, timer interrupts still come from the PIT.
+pub struct Hpet {
+    regs: IoMem,
+    // length of a counter tick in femtoseconds
+    period_fs: u64,
+    counter_64bit: bool,
+}
+
+impl Hpet {
+    /// The current value of the main counter.
+    pub fn counter(&self) -> u64 {
+        self.regs.read::<u64>(MAIN_COUNTER)
+    }
+
+    /// The frequency of the main counter in Hz.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    /// Whether the main counter has 64 bits. A 32 bit counter wraps after
+    /// a few seconds to minutes.
+    pub fn is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    /// Convert a number of counter ticks to nanoseconds.
+    pub fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / FEMTOS_PER_NANO as u128) as u64
+    }
+}
+
+static HPET: Once<Hpet> = Once::new();
+
+/// The HPET, once `init` started it.
+pub fn hpet() -> Option<&'static Hpet> {
+    HPET.get()
+}
+
+/// Map the HPET found in the ACPI tables and start its main counter.
+///
+/// Parses the ACPI tables with `acpi::init` if that has not happened yet.
+pub fn init() -> Result<&'static Hpet, HpetError> {
+    if let Some(hpet) = HPET.get() {
+        return Ok(hpet);
+    }
+    let table = acpi::init().map_err(HpetError::Acpi)?.hpet.ok_or(HpetError::NotPresent)?;
+    let regs = unsafe { ioremap(table.base_address, REGISTERS_SIZE) }.map_err(HpetError::Map)?;
+    let capabilities = regs.read::<u64>(CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::InvalidPeriod(period_fs));
+    }
+    let hpet = Hpet {
+        period_fs,
+        counter_64bit: capabilities & CAP_COUNT_SIZE_64 != 0,
+        regs,
+    };
+    let configuration = hpet.regs.read::<u64>(CONFIGURATION);
+    hpet.regs.write::<u64>(CONFIGURATION, configuration | CONF_ENABLE);
+    Ok(HPET.call_once(|| hpet))
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use spin::Mutex;
 use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
@@ -15,21 +17,59 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
     ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
 });
 
-#[derive(Debug, Clone, Copy)]
+/// The hardware interrupts the kernel handles.
+///
+/// ISA IRQ `n` arrives at vector `PIC_1_OFFSET + n` with either interrupt
+/// controller, `apic::init` routes the I/O APIC entries the same way the
+/// PICs are set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Rtc = PIC_2_OFFSET,
 }
 
 impl InterruptIndex{
-    fn as_u8(self) -> u8 {
+    pub const ALL: [InterruptIndex; 3] = [InterruptIndex::Timer, InterruptIndex::Keyboard, InterruptIndex::Rtc];
+
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
     fn as_usize(self) -> usize{
         usize::from(self as u8)
     }
+
+    /// The ISA IRQ line of the interrupt.
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+/// The interrupt controller hardware interrupts are delivered by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    /// The chained 8259 PICs, set up by `init`.
+    Pic8259,
+    /// The local and I/O APICs, after `apic::init`.
+    Apic,
+}
+
+/// The interrupt controller in use.
+pub fn controller() -> Controller {
+    match crate::apic::local_apic() {
+        Some(_) => Controller::Apic,
+        None => Controller::Pic8259,
+    }
+}
+
+/// Signal the end of the hardware interrupt `index` to whichever controller delivered it.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    match crate::apic::local_apic() {
+        Some(local_apic) => local_apic.end_of_interrupt(),
+        None => unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) },
+    }
 }
 
 use lazy_static::lazy_static;
@@ -37,14 +77,17 @@ lazy_static!{
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(pagefault_handler);
         // unsafe because the validity is secured by caller
         unsafe{
+            idt.page_fault.set_handler_fn(pagefault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_handler);
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
         idt
     };
 }
@@ -65,9 +108,19 @@ extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame,
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: InterruptStackFrame){
     // print!(".");
-    unsafe{
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    crate::time::tick(crate::time::TickSource::Pit);
+    end_of_interrupt(InterruptIndex::Timer);
+}
+
+extern "x86-interrupt" fn rtc_handler(_stack_frame: InterruptStackFrame){
+    if crate::rtc::acknowledge_interrupt() {
+        crate::time::tick(crate::time::TickSource::Rtc);
     }
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+// the local APIC expects no EOI for spurious interrupts
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame){
 }
 
 extern "x86-interrupt" fn keyboard_handler (_stack_frame: InterruptStackFrame){
@@ -96,16 +149,33 @@ extern "x86-interrupt" fn keyboard_handler (_stack_frame: InterruptStackFrame){
         }
     }
 
-    unsafe{
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
 }
 
+// set while the page fault handler runs; a nested fault starts on the same
+// IST stack and has already overwritten the frame of the outer one
+static IN_PAGE_FAULT: AtomicBool = AtomicBool::new(false);
+
 extern "x86-interrupt" fn pagefault_handler (stack_frame: InterruptStackFrame, errnum: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
+    let addr = Cr2::read();
+    if IN_PAGE_FAULT.swap(true, Ordering::Relaxed) {
+        panic!("page fault at {:?} while handling a page fault", addr);
+    }
+    // returning retries the faulting instruction
+    let reason = match crate::memory::fault::handle_page_fault(addr, errnum) {
+        Ok(()) => {
+            IN_PAGE_FAULT.store(false, Ordering::Relaxed);
+            return;
+        }
+        Err(reason) => reason,
+    };
+
     println!("PAGE_FAULT_EXCEPTION OCCURED");
-    println!("Trying to access address: {:?}", Cr2::read());    
+    println!("Trying to access address: {:?}", addr);
     println!("Error number: {:#?}",errnum);
+    println!("Reason: {}", reason);
     println!("Stack Frame: {:#?}", stack_frame);
-    hlt_loop()
-}
\ No newline at end of file
+    // panic instead of halting, so that the panic handler can report the fault
+    panic!("unhandled page fault at {:?}: {}", addr, reason);
+}
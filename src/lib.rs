@@ -5,6 +5,7 @@
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 #![feature(abi_x86_interrupt)]
+#![feature(alloc_error_handler)]
 
 // `alloc` crate ships with Rust compiler as part of std library
 // so no need to add this dependency in Cargo.toml
@@ -23,7 +24,15 @@ pub mod interrupts;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
-// mod pit_8254;
+pub mod backtrace;
+pub mod slab;
+pub mod block;
+pub mod acpi;
+pub mod apic;
+pub mod hpet;
+pub mod pit_8254;
+pub mod rtc;
+pub mod time;
 
 
 pub fn init() {
@@ -31,8 +40,8 @@ pub fn init() {
     gdt::init();
     unsafe{
         interrupts::PICS.lock().initialize();
-        // pit_8254::PIT::new(1).init();
     }
+    time::set_frequency(time::DEFAULT_FREQUENCY);
     x86_64::instructions::interrupts::enable();
 }
 
@@ -111,6 +120,12 @@ pub fn exit_qemu(exit_code: QemuExitCode){
     }
 }
 
+// `__cpuid` is only unsafe on older toolchains
+#[allow(unused_unsafe)]
+pub fn cpuid(leaf: u32) -> core::arch::x86_64::CpuidResult {
+    unsafe { core::arch::x86_64::__cpuid(leaf) }
+}
+
 // Using hlt instruction can make the cpu sleep until next interrupt
 // So it's a little more eco-friedly than merely a infinite loop
 pub fn hlt_loop() -> !{
@@ -10,7 +10,7 @@ use core::panic::PanicInfo;
 
 use alloc::{boxed::Box, rc::Rc,vec, vec::Vec};
 use bootloader::{BootInfo, entry_point,};
-use h_os::{println, init, memory::{self, BootInfoFrameAllocator}, allocator, };
+use h_os::{println, init, memory, allocator, gdt};
 use x86_64::VirtAddr;
 
 
@@ -24,14 +24,43 @@ pub  fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello, rust os World!");
     init();
 
+    memory::report::print_memory_map(&boot_info.memory_map);
+
     let offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe{memory::init(offset)};
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    gdt::init_stacks().expect("IST stack allocation failed");
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    allocator::init_heap()
         .expect("Heap initialization failed");
+    println!("meminfo: {}", memory::meminfo());
+
+    match h_os::acpi::init() {
+        Ok(info) => println!("{}", info),
+        Err(err) => println!("no usable ACPI tables: {}", err),
+    }
+    match h_os::apic::init() {
+        Ok(()) => println!("interrupts are delivered by the APIC"),
+        Err(err) => println!("staying with the 8259 PIC: {}", err),
+    }
+    let clock = h_os::time::init_clocks();
+    h_os::log!("monotonic clock: {}", clock);
+    println!("booted at {}", h_os::time::SystemTime::boot());
+
+    // a second disk, e.g. `-drive file=swap.img,format=raw,index=1`, is used as
+    // swap space, only if asked for, since swap overwrites the whole disk
+    #[cfg(feature = "swap_disk")]
+    {
+        use h_os::block::ata::{AtaDisk, Bus, Drive};
+        match unsafe { AtaDisk::identify(Bus::Primary, Drive::Slave) } {
+            Some(disk) => match memory::swap::enable(Box::new(disk)) {
+                Ok(()) => println!("{}", memory::swap::stats()),
+                Err(err) => println!("enabling swap failed: {:?}", err),
+            },
+            None => println!("no disk for swap found"),
+        }
+    }
 
     let b_list  = Box::new([1,2,3]);
 
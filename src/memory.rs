@@ -1,10 +1,34 @@
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
 use x86_64::{
-    structures::paging::{PageTable, page_table::FrameError, OffsetPageTable, Page, FrameAllocator, Size4KiB, PhysFrame, Mapper, },
+    structures::paging::{PageTable, page_table::FrameError, OffsetPageTable, FrameAllocator, Size4KiB, PhysFrame, },
     VirtAddr, PhysAddr,
 };
 
+pub mod address_space;
+mod bitmap;
+pub mod buddy;
+pub mod cow;
+pub mod fault;
+pub mod mmio;
+mod protect;
+pub mod report;
+pub mod stack;
+pub mod swap;
+pub mod translate;
+pub mod vmm;
+pub mod zone;
+
+pub use address_space::AddressSpace;
+pub use bitmap::BitmapFrameAllocator;
+pub use buddy::BuddyFrameAllocator;
+pub use mmio::{ioremap, IoMem};
+pub use report::{meminfo, MemInfo};
+pub use stack::KernelStack;
+pub use translate::{translate, Translation};
+pub use zone::{Zone, ZoneStats};
+
 /// Initialize a new OffsetPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
@@ -35,55 +59,113 @@ unsafe fn active_4_level_pagetable(offset: VirtAddr)
     &mut *table_ptr
 }
 
+/// The page table mapper and frame allocator used by the whole kernel.
+///
+/// Subsystems that need to map memory after boot (e.g. heap growth) go
+/// through `with_kernel_memory` instead of passing both objects around.
+/// The frame allocator is the buddy allocator, which also provides the
+/// frames of the heap, so that contiguous blocks are available everywhere.
+///
+/// ## Lock order
+///
+/// The heap lock comes before the kernel memory lock, since the heap maps
+/// new pages while it is locked. Code holding the kernel memory lock may
+/// still allocate: the heap only try-locks the kernel memory to grow, so a
+/// full heap makes such an allocation fail instead of deadlocking. The
+/// locks of swap, reference counts and lazy ranges come after both.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+}
 
+impl KernelMemory {
+    /// The frame of the kernel's level 4 table.
+    pub fn level_4_frame(&mut self) -> PhysFrame {
+        let offset = self.mapper.phys_offset();
+        let table = VirtAddr::from_ptr(self.mapper.level_4_table() as *const PageTable);
+        PhysFrame::containing_address(PhysAddr::new((table - offset.as_u64()).as_u64()))
+    }
+}
 
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
 
-/// Allocate Physical Memory
-/// FrameAllocator that returns usable frames from the bootloader's memory map
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+/// Set up the kernel-wide mapper and frame allocator.
+///
+/// This also enables no-execute support and remaps the kernel image so that
+/// no page of it is both writable and executable. The boot stack and the
+/// mapping of the physical memory become no-execute, so that no frame can
+/// be executed through it either.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset` and that
+/// the memory map is valid. Like `init`, it must be only called once, and
+/// `init` must not be used alongside it.
+pub unsafe fn init_kernel_memory(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    protect::enable_nx();
+    address_space::init_pcid();
+    let mut mapper = init(physical_memory_offset);
+    protect::protect_kernel_image(&mut mapper);
+    protect::protect_boot_stack(&mut mapper);
+    protect::protect_physical_memory(&mut mapper, memory_map);
+    address_space::check_user_half(&mut mapper);
+    let mut frame_allocator = BuddyFrameAllocator::init(memory_map, physical_memory_offset);
+    address_space::populate_kernel_half(&mut mapper, &mut frame_allocator);
+    *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frame_allocator });
 }
 
-impl BootInfoFrameAllocator {
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        //get all regions frrom the memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable);
-        
-        // map each region to its address range
-        let addr_ranges = usable_regions
-            .map(|r| r.range.start_addr()..r.range.end_addr());
-        
-        //transform to an iterator of frame start addresses
-        let fram_addresses = addr_ranges
-            .flat_map(|r| r.step_by(4096));
-        
-        // create PhysFrame types from the start addresses
-        fram_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
+/// Whether `init_kernel_memory` has been called.
+pub fn kernel_memory_initialized() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| KERNEL_MEMORY.lock().is_some())
+}
 
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator { memory_map: (memory_map), next: (0) }
-    }
+/// The virtual address the complete physical memory is mapped at.
+///
+/// Panics if `init_kernel_memory` has not been called yet.
+pub fn physical_memory_offset() -> VirtAddr {
+    inspect_kernel_memory(|memory| memory.mapper.phys_offset())
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
+/// Run `f` with exclusive access to the kernel mapper and frame allocator.
+///
+/// Interrupts are disabled while `f` runs, so that an interrupt handler
+/// can never deadlock on the lock held by the code it interrupted.
+///
+/// Panics if `init_kernel_memory` has not been called yet, or if `f` calls
+/// back into `with_kernel_memory`, which would otherwise hang.
+///
+/// `f` may change any mapping, so inactive address spaces flush their TLB
+/// entries on the next switch.
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // with interrupts disabled on the only processor, a held lock can
+        // only belong to the caller itself
+        let mut memory = KERNEL_MEMORY.try_lock().expect("kernel memory locked recursively");
+        let result = f(memory.as_mut().expect("kernel memory is not initialized"));
+        address_space::mappings_changed();
+        result
+    })
+}
+
+/// Like `with_kernel_memory`, for callers that neither remove nor restrict
+/// a mapping, so that address spaces can keep their TLB entries.
+pub(crate) fn inspect_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.try_lock().expect("kernel memory locked recursively");
+        f(memory.as_mut().expect("kernel memory is not initialized"))
+    })
 }
 
 
+/// Like `with_kernel_memory`, but returns `None` instead of spinning if the
+/// lock is held, e.g. by the code a page fault interrupted.
+pub(crate) fn try_with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut memory = KERNEL_MEMORY.try_lock()?;
+        let result = f(memory.as_mut()?);
+        address_space::mappings_changed();
+        Some(result)
+    })
+}
 
 
 // deprecated structures and functions
@@ -91,6 +173,9 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 /// Translates the given virtual address to the mapped physical address, or
 /// `None` if the address is not mapped.
 ///
+/// Superseded by `translate`, which also handles huge pages and reports the
+/// effective flags of the mapping.
+///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `memory_offset`.
@@ -141,18 +226,53 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// Allocate Physical Memory
+/// FrameAllocator that returns usable frames from the bootloader's memory map
+///
+/// Superseded by `BitmapFrameAllocator`, which allocates in O(1) amortized time
+/// and can take frames back.
 #[deprecated]
-pub fn create_mapping_to_vga(
-    page: Page, 
-    mapper: &mut OffsetPageTable, 
-    frame_allocator: & mut impl FrameAllocator<Size4KiB>)
-{
-    use x86_64::structures::paging::PageTableFlags as Flags;
-    let frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flag = Flags::PRESENT | Flags::WRITABLE;
-
-    let map_to_result = unsafe {
-        mapper.map_to(page, frame, flag, frame_allocator)
-    };
-    map_to_result.expect("map_to failed").flush();
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+}
+
+#[allow(deprecated)]
+impl BootInfoFrameAllocator {
+    /// Returns an iterator over the usable frames specified in the memory map.
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        //get all regions frrom the memory map
+        let regions = self.memory_map.iter();
+        let usable_regions = regions
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+        
+        // map each region to its address range
+        let addr_ranges = usable_regions
+            .map(|r| r.range.start_addr()..r.range.end_addr());
+        
+        //transform to an iterator of frame start addresses
+        let fram_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096));
+        
+        // create PhysFrame types from the start addresses
+        fram_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid. The main requirement is that all frames that are marked
+    /// as `USABLE` in it are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        BootInfoFrameAllocator { memory_map: (memory_map), next: (0) }
+    }
+}
+
+#[allow(deprecated)]
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
 }
\ No newline at end of file
@@ -0,0 +1,251 @@
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{cow, inspect_kernel_memory, vmm::Region, with_kernel_memory, BuddyFrameAllocator};
+
+/// Start of the part of the address space that is private to each `AddressSpace`.
+/// The kernel lives in the lower half, so this is not the upper half.
+pub const USER_START: VirtAddr = VirtAddr::new_truncate(0x0000_1000_0000_0000);
+/// End of the user half, exclusive.
+pub const USER_END: VirtAddr = VirtAddr::new_truncate(0x0000_4000_0000_0000);
+
+// the level 4 entries covering the user half
+const USER_ENTRIES: core::ops::Range<usize> = (USER_START.as_u64() >> 39) as usize..(USER_END.as_u64() >> 39) as usize;
+
+// the kernel regions must not end up in the user half, they would not be
+// shared with the address spaces then
+const _: () = {
+    let regions = [Region::Heap, Region::Stacks, Region::Mmio, Region::PerCpu];
+    let mut i = 0;
+    while i < regions.len() {
+        let start = regions[i].start().as_u64();
+        let end = start + regions[i].size();
+        assert!(end <= USER_START.as_u64() || start >= USER_END.as_u64(), "kernel region overlaps the user half");
+        i += 1;
+    }
+};
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+// PCID 0 is used by the kernel table
+static NEXT_PCID: AtomicU16 = AtomicU16::new(1);
+// ids of the tables loaded with a PCID, 0 is the kernel table
+static NEXT_TABLE_ID: AtomicU64 = AtomicU64::new(1);
+// bumped whenever a mapping may have changed, see `mappings_changed`
+static MAPPING_GENERATION: AtomicU64 = AtomicU64::new(0);
+// for every PCID, the table whose TLB entries it tags and the mapping
+// generation they are known to be up to date with
+static PCID_CONTENTS: Mutex<[Option<(u64, u64)>; 4096]> = Mutex::new([None; 4096]);
+
+/// Enable process-context identifiers if the CPU supports them.
+pub(super) fn init_pcid() {
+    let supported = crate::cpuid(1).ecx & (1 << 17) != 0;
+    if supported && Cr3::read_raw().1 == 0 {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+        PCID_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Whether CR3 switches are tagged with a PCID.
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Note that a mapping may have been removed or restricted, so that the
+/// TLB entries of every other PCID are flushed on their next switch.
+pub(super) fn mappings_changed() {
+    MAPPING_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Make sure that no mapping of the bootloader lies in the user half.
+pub(super) fn check_user_half(mapper: &mut OffsetPageTable) {
+    for index in USER_ENTRIES {
+        assert!(mapper.level_4_table()[index].is_unused(), "level 4 entry {} of the user half is in use", index);
+    }
+}
+
+// a PCID handed out twice tags the entries of two tables, which is slower
+// but never wrong, since the owner is checked on every switch
+/// Give every level 4 entry outside of the user half a level 3 table.
+///
+/// Address spaces copy these entries, so every kernel mapping made later
+/// shows up in all of them right away, even in the active one.
+pub(super) fn populate_kernel_half(mapper: &mut OffsetPageTable, allocator: &mut impl FrameAllocator<Size4KiB>) {
+    let offset = mapper.phys_offset();
+    for (index, entry) in mapper.level_4_table().iter_mut().enumerate() {
+        if USER_ENTRIES.contains(&index) || !entry.is_unused() {
+            continue;
+        }
+        let frame = allocator.allocate_frame().expect("no frame left for the kernel page tables");
+        unsafe { table_at(offset, frame) }.zero();
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+}
+
+fn next_pcid() -> u16 {
+    NEXT_PCID.fetch_add(1, Ordering::Relaxed) % 4095 + 1
+}
+
+/// Load `frame` into CR3, tagged with `pcid` if PCIDs are enabled. The TLB
+/// entries of `pcid` are kept only if they are still up to date.
+unsafe fn write_cr3(frame: PhysFrame, pcid: u16, id: u64) {
+    if !pcid_enabled() {
+        Cr3::write(frame, Cr3Flags::empty());
+        return;
+    }
+    let keep = x86_64::instructions::interrupts::without_interrupts(|| {
+        let generation = MAPPING_GENERATION.load(Ordering::Relaxed);
+        let mut contents = PCID_CONTENTS.lock();
+        // the entries of the current PCID were kept up to date by `invlpg`
+        let current = Cr3::read_raw().1 as usize;
+        if let Some((_, updated)) = &mut contents[current] {
+            *updated = generation;
+        }
+        let keep = contents[pcid as usize] == Some((id, generation));
+        contents[pcid as usize] = Some((id, generation));
+        keep
+    });
+    let no_flush = if keep { 1 << 63 } else { 0 };
+    let value = frame.start_address().as_u64() | pcid as u64 | no_flush;
+    core::arch::asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags));
+}
+
+/// A set of page tables with a private user half and the kernel mappings
+/// shared with every other address space.
+///
+/// The level 4 entries outside of the user half are copied from the kernel
+/// table, see `populate_kernel_half`. Dropping an address space frees its
+/// user half page tables and every frame mapped there, unless the frame is
+/// still shared copy-on-write with another table.
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    pcid: u16,
+    id: u64,
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty user half.
+    ///
+    /// Returns `None` if no frame is left for the level 4 table.
+    pub fn new() -> Option<AddressSpace> {
+        let level_4_frame = with_kernel_memory(|memory| {
+            let frame: PhysFrame = memory.frame_allocator.allocate_frame()?;
+            let offset = memory.mapper.phys_offset();
+            let table = unsafe { table_at(offset, frame) };
+            table.zero();
+            Some(frame)
+        })?;
+        let space = AddressSpace {
+            level_4_frame,
+            pcid: next_pcid(),
+            id: NEXT_TABLE_ID.fetch_add(1, Ordering::Relaxed),
+        };
+        space.copy_kernel_half();
+        Some(space)
+    }
+
+    /// The frame of the level 4 table, as loaded into CR3.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Whether this address space is loaded in CR3 right now.
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /// Run `f` with a mapper for this address space and the kernel frame allocator.
+    /// Only the user half may be changed through the mapper.
+    pub fn with_mapper<R>(&mut self, f: impl FnOnce(&mut OffsetPageTable, &mut BuddyFrameAllocator) -> R) -> R {
+        with_kernel_memory(|memory| {
+            let offset = memory.mapper.phys_offset();
+            let mut mapper = unsafe { OffsetPageTable::new(table_at(offset, self.level_4_frame), offset) };
+            f(&mut mapper, &mut memory.frame_allocator)
+        })
+    }
+
+    /// Switch to this address space.
+    ///
+    /// This function is unsafe because the caller must guarantee that no
+    /// references into the user half of the previous address space are used
+    /// afterwards, and that this address space is not dropped while active.
+    pub unsafe fn activate(&self) {
+        write_cr3(self.level_4_frame, self.pcid, self.id);
+    }
+
+    fn copy_kernel_half(&self) {
+        inspect_kernel_memory(|memory| {
+            let offset = memory.mapper.phys_offset();
+            let table = unsafe { table_at(offset, self.level_4_frame) };
+            for (index, entry) in memory.mapper.level_4_table().iter().enumerate() {
+                if !USER_ENTRIES.contains(&index) {
+                    table[index] = entry.clone();
+                }
+            }
+        })
+    }
+}
+
+/// Switch back to the kernel page table.
+///
+/// This function is unsafe for the same reasons as `AddressSpace::activate`.
+pub unsafe fn activate_kernel() {
+    let frame = inspect_kernel_memory(|memory| memory.level_4_frame());
+    write_cr3(frame, 0, 0);
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+        with_kernel_memory(|memory| {
+            let offset = memory.mapper.phys_offset();
+            let allocator = &mut memory.frame_allocator;
+            let table = unsafe { table_at(offset, self.level_4_frame) };
+            for entry in table.iter().take(USER_ENTRIES.end).skip(USER_ENTRIES.start) {
+                if entry.flags().contains(PageTableFlags::PRESENT) {
+                    unsafe { free_table(offset, entry.frame().unwrap(), 3, allocator) };
+                }
+            }
+            unsafe { allocator.deallocate_frame(self.level_4_frame) };
+        })
+    }
+}
+
+unsafe fn table_at(offset: VirtAddr, frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>()
+}
+
+/// Free the page table in `frame` at `level`, everything it maps, and the frame itself.
+unsafe fn free_table(offset: VirtAddr, frame: PhysFrame, level: u8, allocator: &mut BuddyFrameAllocator) {
+    for entry in table_at(offset, frame).iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let addr = entry.addr();
+        match level {
+            1 => {
+                if cow::release_frame(addr) {
+                    allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(addr));
+                }
+            }
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => {
+                allocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(addr));
+            }
+            3 if flags.contains(PageTableFlags::HUGE_PAGE) => {
+                allocator.deallocate_frame(PhysFrame::<Size1GiB>::containing_address(addr));
+            }
+            _ => free_table(offset, PhysFrame::containing_address(addr), level - 1, allocator),
+        }
+    }
+    allocator.deallocate_frame(frame);
+}
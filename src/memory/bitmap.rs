@@ -0,0 +1,133 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB, PageSize},
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Frame allocator backed by a bitmap with one bit per physical frame.
+///
+/// A set bit means the frame is in use (or not usable at all), a cleared bit
+/// means the frame is free. The bitmap itself lives in the first usable region
+/// that is large enough to hold it and is accessed through the physical memory
+/// offset mapping, so no heap is needed to build it.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    free_frames: usize,
+    // index of the first word that may contain a free bit
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a BitmapFrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. All frames that are marked as `USABLE` in the
+    /// memory map must really be unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        // only frames below the end of the last usable region need a bit
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let bitmap_bytes = (word_count * core::mem::size_of::<u64>()) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // steal the first usable region that can hold the whole bitmap
+        let bitmap_start = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .map(|r| r.range.start_addr())
+            .expect("no usable region is large enough for the frame bitmap");
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, word_count);
+
+        // everything is used until the memory map says otherwise
+        bitmap.fill(!0);
+
+        let mut allocator = BitmapFrameAllocator { bitmap, frame_count, free_frames: 0, next_word: 0 };
+        for region in usable_regions() {
+            for frame in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.clear(frame as usize);
+            }
+        }
+
+        let first_bitmap_frame = bitmap_start / FRAME_SIZE;
+        for frame in first_bitmap_frame..first_bitmap_frame + bitmap_frames {
+            allocator.set(frame as usize);
+        }
+
+        allocator
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames covered by the bitmap, usable or not.
+    pub fn total_frames(&self) -> usize {
+        self.frame_count
+    }
+
+    fn is_set(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        debug_assert!(!self.is_set(frame));
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn clear(&mut self, frame: usize) {
+        debug_assert!(self.is_set(frame));
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // words before `next_word` are known to be full, so the scan only
+        // moves forward until a frame is deallocated again
+        for word_index in self.next_word..self.bitmap.len() {
+            let word = self.bitmap[word_index];
+            if word == !0 {
+                continue;
+            }
+            let frame = word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize;
+            if frame >= self.frame_count {
+                break;
+            }
+            self.next_word = word_index;
+            self.set(frame);
+            return Some(PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE)));
+        }
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(index < self.frame_count, "deallocated frame {:?} is not managed by this allocator", frame);
+        assert!(self.is_set(index), "double free of frame {:?}", frame);
+
+        self.clear(index);
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+    }
+}
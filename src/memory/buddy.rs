@@ -0,0 +1,322 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::zone::{Zone, ZoneStats};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Largest block order handed out by the buddy allocator.
+///
+/// A block of order `n` consists of `2^n` contiguous 4 KiB frames, so the
+/// largest block is 1 GiB, which is exactly one `Size1GiB` huge frame.
+pub const MAX_ORDER: usize = 18;
+
+// marks the end of a free list
+const NIL: u64 = u64::MAX;
+// `block_order` value of frames the allocator does not manage
+const RESERVED: u8 = u8::MAX;
+
+/// Intrusive free list node, stored in the first bytes of every free block.
+#[repr(C)]
+struct FreeNode {
+    next: u64,
+    prev: u64,
+}
+
+/// Buddy-system allocator for physically contiguous, naturally aligned runs of frames.
+///
+/// Free blocks are kept in one doubly linked list per order. The list nodes live
+/// inside the free blocks themselves and are accessed through the physical memory
+/// offset mapping. A per-frame byte array records which frames start a free block
+/// and of which order, so that the buddy of a freed block can be found and merged
+/// in O(1), and which frames are not managed at all.
+///
+/// Every `Zone` has free lists of its own and blocks never span two zones,
+/// so that allocations can be restricted to low memory.
+pub struct BuddyFrameAllocator {
+    physical_memory_offset: VirtAddr,
+    free_lists: [[u64; MAX_ORDER + 1]; Zone::ALL.len()],
+    // `order + 1` if the frame starts a free block, `RESERVED` if it is not
+    // managed by the allocator and 0 otherwise
+    block_order: &'static mut [u8],
+    free_frames: [usize; Zone::ALL.len()],
+    total_frames: [usize; Zone::ALL.len()],
+}
+
+impl BuddyFrameAllocator {
+    /// Create a BuddyFrameAllocator from the passed memory map.
+    ///
+    /// This function is unsafe because the caller must guarantee that the passed
+    /// memory map is valid and that the complete physical memory is mapped at
+    /// `physical_memory_offset`. All frames that are marked as `USABLE` in the
+    /// memory map must really be unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = max_addr / FRAME_SIZE;
+        let metadata_frames = (frame_count + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // the block order table takes one byte per frame
+        let metadata_start = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= metadata_frames)
+            .map(|r| r.range.start_frame_number)
+            .expect("no usable region is large enough for the buddy allocator metadata");
+        let metadata_end = metadata_start + metadata_frames;
+
+        let metadata_ptr = (physical_memory_offset + metadata_start * FRAME_SIZE).as_mut_ptr::<u8>();
+        let block_order = core::slice::from_raw_parts_mut(metadata_ptr, frame_count as usize);
+        block_order.fill(RESERVED);
+
+        let mut allocator = BuddyFrameAllocator {
+            physical_memory_offset,
+            free_lists: [[NIL; MAX_ORDER + 1]; Zone::ALL.len()],
+            block_order,
+            free_frames: [0; Zone::ALL.len()],
+            total_frames: [0; Zone::ALL.len()],
+        };
+
+        for region in usable_regions() {
+            let start = region.range.start_frame_number;
+            let end = region.range.end_frame_number;
+            if start < metadata_end && metadata_start < end {
+                allocator.add_frames(start, metadata_start);
+                allocator.add_frames(metadata_end, end);
+            } else {
+                allocator.add_frames(start, end);
+            }
+        }
+        allocator.total_frames = allocator.free_frames;
+
+        allocator
+    }
+
+    /// Allocate `2^order` physically contiguous frames, aligned to their size.
+    ///
+    /// Higher zones are used first, so that low memory stays available for
+    /// devices that need it. Returns the first frame of the block.
+    pub fn allocate_frames(&mut self, order: usize) -> Option<PhysFrame> {
+        self.allocate_frames_in(Zone::Normal, order)
+    }
+
+    /// Like `allocate_frames`, but only from `zone` or the zones below it.
+    pub fn allocate_frames_in(&mut self, zone: Zone, order: usize) -> Option<PhysFrame> {
+        if order > MAX_ORDER {
+            return None;
+        }
+
+        let (zone, mut current) = Zone::ALL[..=zone.index()].iter().rev()
+            .find_map(|&z| {
+                (order..=MAX_ORDER).find(|&o| self.free_lists[z.index()][o] != NIL).map(|o| (z, o))
+            })?;
+        let block = self.free_lists[zone.index()][current] / FRAME_SIZE;
+        self.remove(block, current);
+
+        // split the block, giving the upper halves back until it has the requested size
+        while current > order {
+            current -= 1;
+            self.push(block + (1 << current), current);
+        }
+
+        self.free_frames[zone.index()] -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(block * FRAME_SIZE)))
+    }
+
+    /// Return a block previously obtained from `allocate_frames` with the same `order`.
+    ///
+    /// ## Safety
+    ///
+    /// The caller must ensure that no frame of the block is still in use.
+    pub unsafe fn deallocate_frames(&mut self, frame: PhysFrame, order: usize) {
+        let mut block = frame.start_address().as_u64() / FRAME_SIZE;
+        assert!(order <= MAX_ORDER, "invalid block order {}", order);
+        assert!(block % (1 << order) == 0, "frame {:?} is not aligned to order {}", frame, order);
+        assert!(block + (1 << order) <= self.block_order.len() as u64,
+            "frame {:?} is not managed by this allocator", frame);
+        assert!(!self.overlaps_free(block, order), "double free of frame {:?}", frame);
+
+        let zone = zone_of(block);
+        self.free_frames[zone.index()] += 1 << order;
+
+        // merge with the buddy as long as it is free, of the same size and in the same zone
+        let mut current = order;
+        while current < MAX_ORDER {
+            let buddy = block ^ (1 << current);
+            if buddy as usize >= self.block_order.len()
+                || self.block_order[buddy as usize] != current as u8 + 1
+                || zone_of(buddy) != zone
+            {
+                break;
+            }
+            self.remove(buddy, current);
+            block = block.min(buddy);
+            current += 1;
+        }
+        self.push(block, current);
+    }
+
+    /// Whether `frame` is part of a free block, or not managed by the allocator.
+    pub fn is_free(&self, frame: PhysFrame) -> bool {
+        let block = frame.start_address().as_u64() / FRAME_SIZE;
+        block >= self.block_order.len() as u64 || self.overlaps_free(block, 0)
+    }
+
+    /// Whether any frame of the block of `2^order` frames at `block` is free
+    /// or reserved.
+    ///
+    /// Only the first frame of a free block is marked, so both the free
+    /// blocks that start inside the block and the larger ones that enclose
+    /// it are looked for.
+    fn overlaps_free(&self, block: u64, order: usize) -> bool {
+        let inside = &self.block_order[block as usize..(block + (1 << order)) as usize];
+        inside.iter().any(|&mark| mark != 0)
+            || (order + 1..=MAX_ORDER).any(|enclosing| {
+                let head = block & !((1 << enclosing) - 1);
+                self.block_order[head as usize] == enclosing as u8 + 1
+            })
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames.iter().sum()
+    }
+
+    /// Number of frames managed by the allocator, free or not.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames.iter().sum()
+    }
+
+    /// Frame usage of the given zone.
+    pub fn zone_stats(&self, zone: Zone) -> ZoneStats {
+        ZoneStats {
+            zone,
+            total_frames: self.total_frames[zone.index()],
+            free_frames: self.free_frames[zone.index()],
+        }
+    }
+
+    /// Number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        Zone::ALL.iter().map(|&zone| self.free_blocks_in(zone, order)).sum()
+    }
+
+    /// Number of free blocks of the given order in `zone`.
+    pub fn free_blocks_in(&self, zone: Zone, order: usize) -> usize {
+        let mut count = 0;
+        let mut addr = self.free_lists[zone.index()][order];
+        while addr != NIL {
+            count += 1;
+            addr = unsafe { (*self.node(addr / FRAME_SIZE)).next };
+        }
+        count
+    }
+
+    /// Give the frames `start..end` to the allocator, split into the largest
+    /// aligned blocks that fit without crossing a zone boundary.
+    fn add_frames(&mut self, start: u64, end: u64) {
+        if start < end {
+            self.block_order[start as usize..end as usize].fill(0);
+        }
+        for zone in Zone::ALL {
+            let mut start = start.max(zone.start().as_u64() / FRAME_SIZE);
+            let end = end.min(zone.end().as_u64() / FRAME_SIZE);
+            while start < end {
+                let align_order = start.trailing_zeros() as usize;
+                let size_order = (63 - (end - start).leading_zeros()) as usize;
+                let order = align_order.min(size_order).min(MAX_ORDER);
+
+                self.push(start, order);
+                self.free_frames[zone.index()] += 1 << order;
+                start += 1 << order;
+            }
+        }
+    }
+
+    fn node(&self, block: u64) -> *mut FreeNode {
+        (self.physical_memory_offset + block * FRAME_SIZE).as_mut_ptr()
+    }
+
+    fn push(&mut self, block: u64, order: usize) {
+        let list = &mut self.free_lists[zone_of(block).index()][order];
+        let head = *list;
+        *list = block * FRAME_SIZE;
+        unsafe {
+            self.node(block).write(FreeNode { next: head, prev: NIL });
+            if head != NIL {
+                (*self.node(head / FRAME_SIZE)).prev = block * FRAME_SIZE;
+            }
+        }
+        self.block_order[block as usize] = order as u8 + 1;
+    }
+
+    fn remove(&mut self, block: u64, order: usize) {
+        unsafe {
+            let FreeNode { next, prev } = self.node(block).read();
+            if prev == NIL {
+                self.free_lists[zone_of(block).index()][order] = next;
+            } else {
+                (*self.node(prev / FRAME_SIZE)).next = next;
+            }
+            if next != NIL {
+                (*self.node(next / FRAME_SIZE)).prev = prev;
+            }
+        }
+        self.block_order[block as usize] = 0;
+    }
+}
+
+fn zone_of(block: u64) -> Zone {
+    Zone::containing(PhysAddr::new(block * FRAME_SIZE))
+}
+
+/// Order of the smallest block that holds `S`-sized frames.
+fn order_of<S: PageSize>() -> usize {
+    (S::SIZE / FRAME_SIZE).trailing_zeros() as usize
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate_frames(0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_frames(order_of::<Size2MiB>())
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_frames(order_of::<Size1GiB>())
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate_frames(frame, 0);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate_frames(PhysFrame::containing_address(frame.start_address()), order_of::<Size2MiB>());
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate_frames(PhysFrame::containing_address(frame.start_address()), order_of::<Size1GiB>());
+    }
+}
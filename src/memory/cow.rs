@@ -0,0 +1,250 @@
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult, UnmapError}, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr,
+};
+
+use super::{fault::FaultError, BuddyFrameAllocator};
+
+/// Marks a page that is shared copy-on-write, in one of the PTE bits left to the OS.
+///
+/// Such a page is mapped read-only. The first write to it faults and gets
+/// a private copy of the frame, unless no one else uses the frame anymore.
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+// the number of frames that can be shared at the same time
+const MAX_SHARED_FRAMES: usize = 4096;
+
+/// Reference counts of shared frames.
+///
+/// Only frames with more than one reference are stored, every other frame
+/// implicitly has a single one. The table uses open addressing with linear
+/// probing, keyed by frame number.
+struct RefCounts {
+    // (frame number, references beyond the first)
+    slots: [Option<(u64, u32)>; MAX_SHARED_FRAMES],
+}
+
+impl RefCounts {
+    const fn new() -> Self {
+        RefCounts { slots: [None; MAX_SHARED_FRAMES] }
+    }
+
+    fn probe(number: u64) -> impl Iterator<Item = usize> {
+        let start = (number as usize).wrapping_mul(0x9e37_79b9) % MAX_SHARED_FRAMES;
+        (0..MAX_SHARED_FRAMES).map(move |i| (start + i) % MAX_SHARED_FRAMES)
+    }
+
+    fn find(&self, number: u64) -> Option<usize> {
+        for index in Self::probe(number) {
+            match self.slots[index] {
+                Some((n, _)) if n == number => return Some(index),
+                None => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn get(&self, number: u64) -> u32 {
+        self.find(number).map_or(1, |index| self.slots[index].unwrap().1 + 1)
+    }
+
+    fn increment(&mut self, number: u64) -> bool {
+        if let Some(index) = self.find(number) {
+            self.slots[index].as_mut().unwrap().1 += 1;
+            return true;
+        }
+        match Self::probe(number).find(|&index| self.slots[index].is_none()) {
+            Some(index) => {
+                self.slots[index] = Some((number, 1));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop one reference, returning whether it was the last one.
+    fn decrement(&mut self, number: u64) -> bool {
+        let index = match self.find(number) {
+            Some(index) => index,
+            None => return true,
+        };
+        let count = &mut self.slots[index].as_mut().unwrap().1;
+        *count -= 1;
+        if *count == 0 {
+            self.remove(index);
+        }
+        false
+    }
+
+    // remove the slot and move later entries of its probe chain up,
+    // so that lookups never stop at the hole too early
+    fn remove(&mut self, mut hole: usize) {
+        self.slots[hole] = None;
+        let mut index = hole;
+        loop {
+            index = (index + 1) % MAX_SHARED_FRAMES;
+            let (number, _) = match self.slots[index] {
+                Some(entry) => entry,
+                None => return,
+            };
+            let home = Self::probe(number).next().unwrap();
+            // the entry may fill the hole if the hole lies between its home and its slot
+            let distance = |from: usize, to: usize| (to + MAX_SHARED_FRAMES - from) % MAX_SHARED_FRAMES;
+            if distance(home, hole) < distance(home, index) {
+                self.slots[hole] = self.slots[index].take();
+                hole = index;
+            }
+        }
+    }
+}
+
+static REF_COUNTS: Mutex<RefCounts> = Mutex::new(RefCounts::new());
+
+/// The number of page table entries that map `frame`.
+pub fn ref_count(frame: PhysFrame) -> u32 {
+    x86_64::instructions::interrupts::without_interrupts(|| REF_COUNTS.lock().get(frame_number(frame.start_address())))
+}
+
+/// Drop a reference to the frame starting at `addr`, returning whether it
+/// was the last one and the frame can be freed.
+pub(crate) fn release_frame(addr: PhysAddr) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| REF_COUNTS.lock().decrement(frame_number(addr)))
+}
+
+fn frame_number(addr: PhysAddr) -> u64 {
+    addr.as_u64() / Size4KiB::SIZE
+}
+
+/// Why a page could not be shared.
+#[derive(Debug)]
+pub enum ShareError {
+    /// The source page is not mapped, or not with a 4 KiB page.
+    NotMapped,
+    /// The reference count table is full.
+    TooManySharedFrames,
+    /// Mapping the page in the destination failed.
+    Map(MapToError<Size4KiB>),
+}
+
+/// Map the frame behind `src_page` in `src` at `dst_page` in `dst` as well.
+///
+/// Writable pages become read-only copy-on-write pages in both tables, so
+/// that whichever side writes first gets its own copy. Read-only pages are
+/// simply shared.
+pub fn share(
+    src: &mut OffsetPageTable,
+    src_page: Page,
+    dst: &mut OffsetPageTable,
+    dst_page: Page,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> Result<(), ShareError> {
+    let (frame, mut flags) = match src.translate(src_page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+        _ => return Err(ShareError::NotMapped),
+    };
+
+    let increased = x86_64::instructions::interrupts::without_interrupts(|| {
+        REF_COUNTS.lock().increment(frame_number(frame.start_address()))
+    });
+    if !increased {
+        return Err(ShareError::TooManySharedFrames);
+    }
+
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags = (flags - PageTableFlags::WRITABLE) | COW;
+        unsafe {
+            src.update_flags(src_page, flags)
+                .expect("source page was just translated")
+                .flush();
+        }
+    }
+
+    // the parent entries only grant what the leaf entries allow anyway
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    let result = unsafe {
+        dst.map_to_with_table_flags(dst_page, frame, flags, parent_flags, frame_allocator)
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            release_frame(frame.start_address());
+            Err(ShareError::Map(err))
+        }
+    }
+}
+
+/// Unmap `page` from `mapper`, freeing its frame once no other table maps it.
+pub fn unmap(
+    mapper: &mut OffsetPageTable,
+    page: Page,
+    frame_allocator: &mut BuddyFrameAllocator,
+) -> Result<(), UnmapError> {
+    let (frame, flush) = mapper.unmap(page)?;
+    flush.flush();
+    if release_frame(frame.start_address()) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    Ok(())
+}
+
+/// Resolve a write fault on a copy-on-write page of `mapper`.
+///
+/// If the frame is still shared, the page gets a copy of it. Otherwise the
+/// faulting side is the last user and the page just becomes writable again.
+pub(crate) fn handle_write_fault(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BuddyFrameAllocator,
+    page: Page,
+) -> Result<(), FaultError> {
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } if flags.contains(COW) => (frame, flags),
+        _ => return Err(FaultError::ProtectionViolation),
+    };
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+
+    let shared = x86_64::instructions::interrupts::without_interrupts(|| {
+        REF_COUNTS.try_lock().map(|counts| counts.get(frame_number(frame.start_address())) > 1)
+    }).ok_or(FaultError::LockHeld)?;
+
+    if !shared {
+        unsafe {
+            mapper.update_flags(page, flags)
+                .expect("page was just translated")
+                .flush();
+        }
+        return Ok(());
+    }
+
+    let copy: PhysFrame = frame_allocator.allocate_frame().ok_or(FaultError::OutOfMemory)?;
+    let offset = mapper.phys_offset();
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            (offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+            (offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+            Size4KiB::SIZE as usize,
+        );
+    }
+
+    let (_, flush) = mapper.unmap(page).expect("page was just translated");
+    flush.ignore();
+    unsafe {
+        mapper.map_to(page, copy, flags, frame_allocator)
+            .expect("the page tables of the page still exist")
+            .flush();
+    }
+
+    // the other users may have dropped the frame in the meantime
+    if release_frame(frame.start_address()) {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    Ok(())
+}
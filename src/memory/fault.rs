@@ -0,0 +1,116 @@
+use core::fmt;
+
+use x86_64::{
+    structures::{idt::PageFaultErrorCode, paging::{Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size4KiB}},
+    registers::control::Cr3,
+    VirtAddr,
+};
+
+use super::{active_4_level_pagetable, cow, swap, try_with_kernel_memory, vmm, BuddyFrameAllocator, KernelMemory};
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address is not part of any lazily backed range.
+    NotMapped,
+    /// The page is present, but the access is not allowed by its flags.
+    ProtectionViolation,
+    /// A write to a lazily backed range that is not writable.
+    WriteToReadOnly,
+    /// An instruction fetch from a lazily backed range, which is never executable.
+    InstructionFetch,
+    /// A user mode access to a lazily backed kernel range.
+    UserAccess,
+    /// No frame or page table could be allocated for the page.
+    OutOfMemory,
+    /// The page was swapped out and reading it back from the swap device failed.
+    SwapIn,
+    /// The fault hit code holding a memory management lock, so it can not be
+    /// resolved without deadlocking.
+    LockHeld,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            FaultError::NotMapped => "address is not mapped",
+            FaultError::ProtectionViolation => "access violates the page protection",
+            FaultError::WriteToReadOnly => "write to a read-only page",
+            FaultError::InstructionFetch => "instruction fetch from a no-execute page",
+            FaultError::UserAccess => "user mode access to a kernel page",
+            FaultError::OutOfMemory => "out of memory while backing the page",
+            FaultError::SwapIn => "failed to read the page back from swap",
+            FaultError::LockHeld => "fault while holding a memory management lock",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Try to resolve a page fault at `addr`.
+///
+/// Faults on untouched pages of ranges registered with `vmm::map_lazy` are
+/// resolved by mapping a zeroed frame, or by reading the page back if
+/// `swap::reclaim` swapped it out, and write faults on copy-on-write pages
+/// by copying the frame. Afterwards the faulting instruction can
+/// simply be retried. Everything else is an error.
+pub fn handle_page_fault(addr: VirtAddr, error: PageFaultErrorCode) -> Result<(), FaultError> {
+    if error.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && !error.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        {
+            let page = Page::containing_address(addr);
+            return try_with_kernel_memory(|memory| with_active_mapper(memory, |mapper, frame_allocator| {
+                cow::handle_write_fault(mapper, frame_allocator, page)
+            })).ok_or(FaultError::LockHeld)?;
+        }
+        return Err(FaultError::ProtectionViolation);
+    }
+    let flags = vmm::lazy_flags(addr).ok_or(FaultError::NotMapped)?;
+
+    if error.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        return Err(FaultError::InstructionFetch);
+    }
+    if error.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !flags.contains(PageTableFlags::WRITABLE) {
+        return Err(FaultError::WriteToReadOnly);
+    }
+    if error.contains(PageFaultErrorCode::USER_MODE) && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err(FaultError::UserAccess);
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    try_with_kernel_memory(|memory| {
+        if let Some(result) = swap::swap_in(memory, page) {
+            return result;
+        }
+        vmm::map_page(memory, page, flags | PageTableFlags::WRITABLE)
+            .map_err(|_| FaultError::OutOfMemory)?;
+        // frames are handed out dirty, so clear the page before anyone sees it
+        unsafe {
+            core::ptr::write_bytes(page.start_address().as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize);
+        }
+        if !flags.contains(PageTableFlags::WRITABLE) {
+            unsafe {
+                memory.mapper.update_flags(page, flags | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE)
+                    .expect("page was just mapped")
+                    .flush();
+            }
+        }
+        Ok(())
+    }).ok_or(FaultError::LockHeld)?
+}
+
+/// Run `f` with a mapper for the page table the CPU currently uses, which
+/// need not be the kernel mapper once there are several address spaces.
+fn with_active_mapper<R>(
+    memory: &mut KernelMemory,
+    f: impl FnOnce(&mut OffsetPageTable, &mut BuddyFrameAllocator) -> R,
+) -> R {
+    let offset = memory.mapper.phys_offset();
+    let (active, _) = Cr3::read();
+    if active == memory.level_4_frame() {
+        return f(&mut memory.mapper, &mut memory.frame_allocator);
+    }
+    // the kernel memory lock is held, so nobody else modifies the active table
+    let mut mapper = unsafe { OffsetPageTable::new(active_4_level_pagetable(offset), offset) };
+    f(&mut mapper, &mut memory.frame_allocator)
+}
@@ -0,0 +1,149 @@
+use core::mem;
+
+use x86_64::{
+    structures::paging::{mapper::MapToError, Mapper, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{inspect_kernel_memory, try_with_kernel_memory, vmm::{self, Region, VirtRange}};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Flags every device memory mapping is made with.
+///
+/// Device registers must neither be cached nor written back late, and never
+/// hold code.
+pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// Why `ioremap` failed.
+#[derive(Debug)]
+pub enum IoremapError {
+    /// The `Mmio` region has no room left for the mapping.
+    OutOfVirtualSpace,
+    /// A page could not be mapped, e.g. because no frame was left for a page table.
+    Map(MapToError<Size4KiB>),
+}
+
+/// A mapping of device memory into the `Mmio` region, unmapped on drop.
+///
+/// All accesses are volatile, so the compiler neither drops nor merges
+/// them. Unmapping never frees the frames, they belong to the device.
+#[derive(Debug)]
+pub struct IoMem {
+    range: VirtRange,
+    phys_addr: PhysAddr,
+    // distance of `phys_addr` from the start of its page
+    offset: u64,
+    size: usize,
+}
+
+/// Map `size` bytes of device memory starting at `phys_addr` uncached.
+///
+/// The range does not need to be page aligned, the pages around it are
+/// mapped as well but stay out of reach of the accessors.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// physical range belongs to a device, or to memory that is not used as
+/// anything else, since writes through the mapping bypass Rust's aliasing
+/// rules.
+pub unsafe fn ioremap(phys_addr: PhysAddr, size: usize) -> Result<IoMem, IoremapError> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys_addr);
+    let offset = phys_addr - first.start_address();
+    let pages = (offset + size as u64 + PAGE_SIZE - 1) / PAGE_SIZE;
+    let range = vmm::reserve(Region::Mmio, pages * PAGE_SIZE).ok_or(IoremapError::OutOfVirtualSpace)?;
+
+    // only the new pages are ever unmapped again, and no TLB can hold them yet
+    let result = inspect_kernel_memory(|memory| {
+        for (mapped, page) in range.pages().enumerate() {
+            let frame = first + mapped as u64;
+            let result = memory.mapper.map_to(page, frame, MMIO_FLAGS, &mut memory.frame_allocator);
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    for page in range.pages().take(mapped) {
+                        memory.mapper.unmap(page).expect("page was just mapped").1.flush();
+                    }
+                    return Err(IoremapError::Map(err));
+                }
+            }
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        vmm::release(range);
+        return Err(err);
+    }
+    Ok(IoMem { range, phys_addr, offset, size })
+}
+
+impl IoMem {
+    /// The virtual address `phys_addr` is mapped at.
+    pub fn base(&self) -> VirtAddr {
+        self.range.start + self.offset
+    }
+
+    /// The physical address the mapping was requested for.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys_addr
+    }
+
+    /// The number of bytes the mapping was requested for.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read a `T` at `offset` bytes from the start of the mapping.
+    ///
+    /// Panics if the value does not lie completely inside the mapping or is
+    /// not aligned for `T`.
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Write a `T` at `offset` bytes from the start of the mapping.
+    ///
+    /// Panics under the same conditions as `read`.
+    pub fn write<T: Copy>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    fn ptr<T>(&self, offset: usize) -> *mut T {
+        assert!(matches!(offset.checked_add(mem::size_of::<T>()), Some(end) if end <= self.size),
+            "access at offset {:#x} is outside of the {} byte mapping", offset, self.size);
+        let addr = self.base() + offset as u64;
+        assert!(addr.is_aligned(mem::align_of::<T>() as u64), "unaligned access at {:?}", addr);
+        addr.as_mut_ptr()
+    }
+}
+
+impl Drop for IoMem {
+    fn drop(&mut self) {
+        // dropping must not panic if the kernel memory lock is held already
+        let unmapped = try_with_kernel_memory(|memory| {
+            let mut unmapped = true;
+            for page in self.range.pages() {
+                // the frames belong to the device, so they are not freed
+                match memory.mapper.unmap(page) {
+                    Ok((_, flush)) => flush.flush(),
+                    Err(err) => {
+                        crate::log!("failed to unmap {:?}: {:?}", page, err);
+                        unmapped = false;
+                    }
+                }
+            }
+            unmapped
+        });
+        if unmapped.is_none() {
+            crate::log!("kernel memory is locked, leaking the mapping at {:?}", self.range.start);
+        }
+        // a range that is still partly mapped is leaked, so that it is never
+        // handed out again
+        if unmapped == Some(true) {
+            vmm::release(self.range);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    instructions::tlb,
+    registers::control::{Cr0, Cr0Flags, Efer, EferFlags},
+    structures::paging::{
+        mapper::{MappedFrame, MapperFlush}, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::translate::translate;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// The part of the ELF file header needed to find the program headers.
+#[allow(dead_code)]
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = Page::<Size4KiB>::containing_address(VirtAddr::new(self.vaddr));
+        let end = Page::containing_address(VirtAddr::new(self.vaddr + self.memsz - 1));
+        Page::range_inclusive(start, end)
+    }
+
+    fn contains(&self, page: Page) -> bool {
+        let start = page.start_address().as_u64();
+        start < self.vaddr + self.memsz && self.vaddr < start + 4096
+    }
+}
+
+extern "C" {
+    // defined by the linker, the ELF header is loaded as part of the first segment
+    static __ehdr_start: ElfHeader;
+}
+
+/// The loadable segments of the running kernel image.
+fn kernel_segments() -> impl Iterator<Item = &'static ProgramHeader> + Clone {
+    let header = unsafe { &*core::ptr::addr_of!(__ehdr_start) };
+    assert_eq!(header.ident[..4], ELF_MAGIC, "kernel ELF header not found");
+    assert_eq!(header.phentsize as usize, core::mem::size_of::<ProgramHeader>());
+
+    let base = header as *const ElfHeader as *const u8;
+    let headers = unsafe {
+        core::slice::from_raw_parts(base.add(header.phoff as usize) as *const ProgramHeader, header.phnum as usize)
+    };
+    headers.iter().filter(|h| h.kind == PT_LOAD && h.memsz > 0)
+}
+
+/// Turn on no-execute support and make the kernel honor read-only pages.
+///
+/// Without `EFER.NXE` the `NO_EXECUTE` bit is reserved, so this must run
+/// before any page is mapped with it.
+pub fn enable_nx() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        // ring 0 ignores missing WRITABLE bits unless CR0.WP is set
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// Remap the kernel image according to the permissions of its ELF segments.
+///
+/// Code ends up read-only and executable, read-only data read-only, and
+/// writable data and bss no-execute, so no kernel page is both writable and
+/// executable.
+pub fn protect_kernel_image(mapper: &mut OffsetPageTable) {
+    let segments = kernel_segments();
+    for segment in segments.clone() {
+        for page in segment.pages() {
+            // a page shared by two segments gets the permissions of both
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            for other in segments.clone().filter(|s| s.contains(page)) {
+                if other.flags & PF_W != 0 {
+                    flags |= PageTableFlags::WRITABLE;
+                }
+                if other.flags & PF_X != 0 {
+                    flags.remove(PageTableFlags::NO_EXECUTE);
+                }
+            }
+            assert!(flags.contains(PageTableFlags::NO_EXECUTE) || !flags.contains(PageTableFlags::WRITABLE),
+                "kernel page at {:#x} is writable and executable", page.start_address().as_u64());
+            unsafe {
+                mapper.update_flags(page, flags)
+                    .expect("kernel image is not mapped with 4KiB pages")
+                    .flush();
+            }
+        }
+    }
+}
+
+// bootloader 0.9 maps 80 pages of stack by default
+const MAX_BOOT_STACK_PAGES: u64 = 512;
+
+/// Mark the stack the bootloader set up, which the kernel still runs on,
+/// no-execute.
+///
+/// The bootloader does not pass its location on, so this takes the run of
+/// 4KiB pages around the stack pointer that are mapped and not part of the
+/// kernel image. The guard page below the stack ends the run.
+pub fn protect_boot_stack(mapper: &mut OffsetPageTable) {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let current = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let mut is_stack = |page: Page| {
+        matches!(translate(mapper, page.start_address()), Some(t) if matches!(t.frame, MappedFrame::Size4KiB(_)))
+            && !kernel_segments().any(|s| s.contains(page))
+    };
+    let (mut bottom, mut top) = (current, current);
+    while current - bottom < MAX_BOOT_STACK_PAGES && is_stack(bottom - 1) {
+        bottom -= 1;
+    }
+    while top - current < MAX_BOOT_STACK_PAGES && is_stack(top + 1) {
+        top += 1;
+    }
+
+    for page in Page::range_inclusive(bottom, top) {
+        let flags = translate(mapper, page.start_address()).expect("stack page was just translated").flags;
+        unsafe {
+            mapper.update_flags(page, flags | PageTableFlags::NO_EXECUTE)
+                .expect("stack page was just translated")
+                .ignore();
+        }
+    }
+    tlb::flush_all();
+}
+
+/// Mark the mapping of the complete physical memory no-execute.
+///
+/// Every frame is reachable through that mapping, writable, including the
+/// ones of the kernel code, the heap and the stacks, so without this any
+/// memory could be executed through its alias there.
+pub fn protect_physical_memory(mapper: &mut OffsetPageTable, memory_map: &MemoryMap) {
+    let end = memory_map.iter().map(|r| r.range.end_addr()).max().unwrap_or(0);
+    let offset = mapper.phys_offset();
+    let mut phys = 0;
+    while phys < end {
+        let addr = offset + phys;
+        let size = match translate(mapper, addr) {
+            Some(translation) => {
+                let flags = translation.flags | PageTableFlags::NO_EXECUTE;
+                let result = unsafe {
+                    match translation.frame {
+                        MappedFrame::Size4KiB(_) => mapper
+                            .update_flags(Page::<Size4KiB>::containing_address(addr), flags)
+                            .map(MapperFlush::ignore),
+                        MappedFrame::Size2MiB(_) => mapper
+                            .update_flags(Page::<Size2MiB>::containing_address(addr), flags)
+                            .map(MapperFlush::ignore),
+                        MappedFrame::Size1GiB(_) => mapper
+                            .update_flags(Page::<Size1GiB>::containing_address(addr), flags)
+                            .map(MapperFlush::ignore),
+                    }
+                };
+                result.expect("physical memory mapping changed while it was walked");
+                translation.frame.size()
+            }
+            None => Size4KiB::SIZE,
+        };
+        // continue after the page, which may have started before `addr`
+        phys = (addr.align_down(size) + size).as_u64() - offset.as_u64();
+    }
+    tlb::flush_all();
+}
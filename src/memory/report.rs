@@ -0,0 +1,121 @@
+use core::fmt;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+
+use super::{inspect_kernel_memory, zone::{Zone, ZoneStats}};
+use crate::{println, serial_println};
+
+// print to the screen and to serial, so that the report survives a hanging VGA console
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        println!($($arg)*);
+        serial_println!($($arg)*);
+    }};
+}
+
+/// A byte count printed with a binary unit, e.g. `127 MiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size(pub u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+        let mut unit = 0;
+        let mut value = self.0;
+        // only switch units while the value stays exact
+        while unit + 1 < UNITS.len() && value >= 1024 && value % 1024 == 0 {
+            value /= 1024;
+            unit += 1;
+        }
+        write!(f, "{} {}", value, UNITS[unit])
+    }
+}
+
+/// Byte totals of the memory map, grouped by who owns the memory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryMapSummary {
+    /// Free for the frame allocator.
+    pub usable: u64,
+    /// The kernel image, its stack and the page tables set up for it.
+    pub kernel: u64,
+    /// The bootloader itself, the boot info and the packaged kernel.
+    pub bootloader: u64,
+    /// Firmware, ACPI, bad memory and everything else.
+    pub reserved: u64,
+}
+
+impl MemoryMapSummary {
+    pub fn new(memory_map: &MemoryMap) -> Self {
+        let mut summary = MemoryMapSummary::default();
+        for region in memory_map.iter() {
+            let size = region.range.end_addr() - region.range.start_addr();
+            match region.region_type {
+                MemoryRegionType::Usable => summary.usable += size,
+                MemoryRegionType::Kernel | MemoryRegionType::KernelStack
+                    | MemoryRegionType::PageTable => summary.kernel += size,
+                MemoryRegionType::Bootloader | MemoryRegionType::BootInfo
+                    | MemoryRegionType::Package => summary.bootloader += size,
+                _ => summary.reserved += size,
+            }
+        }
+        summary
+    }
+}
+
+impl fmt::Display for MemoryMapSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "usable {}, kernel {}, bootloader {}, reserved {}",
+            Size(self.usable), Size(self.kernel), Size(self.bootloader), Size(self.reserved))
+    }
+}
+
+/// Print every region of the memory map and the totals, to the screen and serial.
+pub fn print_memory_map(memory_map: &MemoryMap) {
+    report!("physical memory map:");
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        report!("  {:#012x}-{:#012x} {} {:?}", start, end, Size(end - start), region.region_type);
+    }
+    report!("{}", MemoryMapSummary::new(memory_map));
+}
+
+/// Usage of the physical frames managed by the kernel frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemInfo {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    /// Usage per zone, from the lowest to the highest.
+    pub zones: [ZoneStats; Zone::ALL.len()],
+}
+
+impl MemInfo {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+}
+
+impl fmt::Display for MemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = |frames: usize| Size(frames as u64 * 4096);
+        write!(f, "{} frames ({}), {} free ({}), {} used ({})",
+            self.total_frames, size(self.total_frames),
+            self.free_frames, size(self.free_frames),
+            self.used_frames(), size(self.used_frames()))?;
+        for zone in &self.zones {
+            write!(f, "\n  {}", zone)?;
+        }
+        Ok(())
+    }
+}
+
+/// The current frame usage.
+///
+/// Panics if `init_kernel_memory` has not been called yet.
+pub fn meminfo() -> MemInfo {
+    inspect_kernel_memory(|memory| MemInfo {
+        total_frames: memory.frame_allocator.total_frames(),
+        free_frames: memory.frame_allocator.free_frames(),
+        zones: Zone::ALL.map(|zone| memory.frame_allocator.zone_stats(zone)),
+    })
+}
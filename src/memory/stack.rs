@@ -0,0 +1,86 @@
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::vmm::{self, Region, VirtRange};
+
+/// A kernel stack in the stack region, with an unmapped guard page below it.
+///
+/// Running off the bottom of the stack touches the guard page and raises a
+/// page fault instead of silently overwriting whatever lies below. The stack
+/// is unmapped and its frames are freed on drop.
+#[derive(Debug)]
+pub struct KernelStack {
+    // the reserved range, guard page included
+    range: VirtRange,
+}
+
+impl KernelStack {
+    /// Allocate a stack of `pages` usable pages plus one guard page.
+    pub fn new(pages: u64) -> Result<KernelStack, MapToError<Size4KiB>> {
+        assert!(pages > 0, "a kernel stack needs at least one page");
+        let range = vmm::reserve(Region::Stacks, (pages + 1) * Size4KiB::SIZE)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        // everything but the lowest page, which stays unmapped as the guard
+        let usable = VirtRange { start: range.start + Size4KiB::SIZE, size: range.size - Size4KiB::SIZE };
+        if let Err(err) = vmm::map_range(&usable, PageTableFlags::WRITABLE) {
+            vmm::release(range);
+            return Err(err);
+        }
+        Ok(KernelStack { range })
+    }
+
+    /// Like `new`, but only the topmost page is backed right away.
+    ///
+    /// The other pages get a frame when the stack first grows into them,
+    /// which keeps large but mostly unused stacks cheap.
+    pub fn new_lazy(pages: u64) -> Result<KernelStack, MapToError<Size4KiB>> {
+        assert!(pages > 0, "a kernel stack needs at least one page");
+        let range = vmm::reserve(Region::Stacks, (pages + 1) * Size4KiB::SIZE)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let top = VirtRange { start: range.end() - Size4KiB::SIZE, size: Size4KiB::SIZE };
+        let lazy = VirtRange { start: range.start + Size4KiB::SIZE, size: range.size - 2 * Size4KiB::SIZE };
+        if let Err(err) = vmm::map_range(&top, PageTableFlags::WRITABLE) {
+            vmm::release(range);
+            return Err(err);
+        }
+        if lazy.size > 0 && !vmm::map_lazy(&lazy, PageTableFlags::WRITABLE) {
+            vmm::unmap_range(&top);
+            vmm::release(range);
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        Ok(KernelStack { range })
+    }
+
+    /// The initial stack pointer, the stack grows down from here.
+    pub fn top(&self) -> VirtAddr {
+        self.range.end()
+    }
+
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.range.start + Size4KiB::SIZE
+    }
+
+    /// The unmapped page right below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.range.start)
+    }
+
+    /// Keep the stack mapped forever, e.g. for an IST entry, and return its top.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        core::mem::forget(self);
+        top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vmm::unmap_range(&self.range);
+        vmm::release(self.range);
+    }
+}
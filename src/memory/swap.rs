@@ -0,0 +1,286 @@
+use core::fmt;
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        page_table::PageTableEntry, FrameAllocator, FrameDeallocator, OffsetPageTable, Page, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{cow, fault::FaultError, kernel_memory_initialized, vmm::{self, VirtRange}, with_kernel_memory, KernelMemory};
+use crate::block::{BlockDevice, BLOCK_SIZE};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+const BLOCKS_PER_SLOT: u64 = PAGE_SIZE / BLOCK_SIZE as u64;
+
+/// Marks a not-present page table entry whose page was written to swap, in
+/// one of the PTE bits left to the OS.
+///
+/// The address field of such an entry holds the swap slot instead of a
+/// frame, and the other flags are the ones the page is mapped with again
+/// when it is read back.
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+/// Why swap could not be enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    /// A swap device is set up already.
+    AlreadyEnabled,
+    /// The device can not hold a single page.
+    TooSmall,
+}
+
+/// A block device pages are written to, split into page sized slots.
+struct SwapArea {
+    device: Box<dyn BlockDevice + Send>,
+    // one bit per slot, set while the slot holds a page
+    used: Vec<u64>,
+    slots: usize,
+    used_slots: usize,
+    // where the next reclaim scan continues
+    hand: VirtAddr,
+}
+
+impl SwapArea {
+    fn allocate_slot(&mut self) -> Option<u64> {
+        let (index, word) = self.used.iter_mut().enumerate().find(|(_, word)| **word != u64::MAX)?;
+        let bit = word.trailing_ones() as usize;
+        let slot = index * 64 + bit;
+        if slot >= self.slots {
+            return None;
+        }
+        *word |= 1 << bit;
+        self.used_slots += 1;
+        Some(slot as u64)
+    }
+
+    fn free_slot(&mut self, slot: u64) {
+        let word = &mut self.used[slot as usize / 64];
+        let bit = 1 << (slot % 64);
+        assert!(*word & bit != 0, "double free of swap slot {}", slot);
+        *word &= !bit;
+        self.used_slots -= 1;
+    }
+}
+
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+/// Use `device` as swap space for the ranges registered with `vmm::map_swappable`.
+///
+/// Whatever is on the device is overwritten. Swap can not be turned off again.
+pub fn enable(device: Box<dyn BlockDevice + Send>) -> Result<(), SwapError> {
+    let slots = (device.block_count() / BLOCKS_PER_SLOT) as usize;
+    if slots == 0 {
+        return Err(SwapError::TooSmall);
+    }
+    // allocate before taking the lock, the heap lock comes first
+    let used = vec![0; (slots + 63) / 64];
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        if swap.is_some() {
+            return Err(SwapError::AlreadyEnabled);
+        }
+        *swap = Some(SwapArea { device, used, slots, used_slots: 0, hand: VirtAddr::zero() });
+        Ok(())
+    })
+}
+
+/// Usage of the swap area.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SwapStats {
+    /// Number of pages the swap device can hold, 0 if swap is not enabled.
+    pub total_slots: usize,
+    /// Number of pages that are swapped out right now.
+    pub used_slots: usize,
+}
+
+impl fmt::Display for SwapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "swap: {} of {} pages used", self.used_slots, self.total_slots)
+    }
+}
+
+/// The current swap usage.
+pub fn stats() -> SwapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SWAP.lock().as_ref().map_or(SwapStats::default(), |area| SwapStats {
+            total_slots: area.slots,
+            used_slots: area.used_slots,
+        })
+    })
+}
+
+/// Write up to `count` cold pages of the swappable ranges to swap and free their frames.
+///
+/// Pages are scanned like the hand of a clock, continuing where the last
+/// call stopped. A page whose accessed bit is set gets a second chance: the
+/// bit is cleared and the page is only taken if it is still clear when the
+/// hand comes around again. Copy-on-write pages are never swapped.
+///
+/// Returns the number of pages that were swapped out, which is 0 if swap is
+/// not enabled.
+pub fn reclaim(count: usize) -> usize {
+    if count == 0 || !kernel_memory_initialized() {
+        return 0;
+    }
+    let mut ranges = vmm::swappable_ranges();
+    ranges.sort_unstable_by_key(|r| r.map(|r| r.start));
+    let total_pages: u64 = ranges.iter().flatten().map(|r| r.size / PAGE_SIZE).sum();
+
+    with_kernel_memory(|memory| {
+        let mut swap = SWAP.lock();
+        let area = match swap.as_mut() {
+            Some(area) => area,
+            None => return 0,
+        };
+        let mut reclaimed = 0;
+        // two rounds, so that pages only accessed before the first one are taken in the second
+        for _ in 0..2 * total_pages {
+            let addr = match next_page(&ranges, area.hand) {
+                Some(addr) => addr,
+                None => break,
+            };
+            area.hand = addr + PAGE_SIZE;
+            match swap_out(memory, area, Page::containing_address(addr)) {
+                Some(true) => reclaimed += 1,
+                Some(false) => {}
+                // the swap area is full
+                None => break,
+            }
+            if reclaimed == count {
+                break;
+            }
+        }
+        reclaimed
+    })
+}
+
+/// The first page at or after `addr` in one of the sorted `ranges`, wrapping
+/// around to the first range.
+fn next_page(ranges: &[Option<VirtRange>], addr: VirtAddr) -> Option<VirtAddr> {
+    let mut ranges = ranges.iter().flatten();
+    let first = ranges.clone().next()?;
+    Some(ranges.find(|r| r.end() > addr).map_or(first.start, |r| r.start.max(addr)))
+}
+
+/// Swap out `page` if it is cold, otherwise clear its accessed bit.
+///
+/// Returns whether the page was swapped out, or `None` if there is no free slot.
+fn swap_out(memory: &mut KernelMemory, area: &mut SwapArea, page: Page) -> Option<bool> {
+    let entry = match unsafe { entry_mut(&mut memory.mapper, page) } {
+        Some(entry) => entry,
+        None => return Some(false),
+    };
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || flags.contains(cow::COW) {
+        return Some(false);
+    }
+    if flags.contains(PageTableFlags::ACCESSED) {
+        entry.set_flags(flags - PageTableFlags::ACCESSED);
+        tlb::flush(page.start_address());
+        return Some(false);
+    }
+
+    let slot = area.allocate_slot()?;
+    let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+    let contents = unsafe { frame_contents(memory.mapper.phys_offset(), frame) };
+    if area.device.write_blocks(slot * BLOCKS_PER_SLOT, contents).is_err() {
+        // keep the page in memory, the next scan tries again
+        area.free_slot(slot);
+        return Some(false);
+    }
+
+    let swapped = flags - PageTableFlags::PRESENT - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+    entry.set_addr(PhysAddr::new(slot * PAGE_SIZE), swapped | SWAPPED);
+    tlb::flush(page.start_address());
+    if cow::release_frame(frame.start_address()) {
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+    }
+    Some(true)
+}
+
+/// Read `page` back from swap if it was swapped out.
+///
+/// Returns `None` if the entry of `page` is not a swap entry. Called from
+/// the page fault handler, so it gives up instead of spinning on the swap lock.
+pub(crate) fn swap_in(memory: &mut KernelMemory, page: Page) -> Option<Result<(), FaultError>> {
+    let entry = unsafe { entry_mut(&mut memory.mapper, page) }?;
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || !flags.contains(SWAPPED) {
+        return None;
+    }
+    let slot = entry.addr().as_u64() / PAGE_SIZE;
+
+    let mut swap = match SWAP.try_lock() {
+        Some(swap) => swap,
+        None => return Some(Err(FaultError::LockHeld)),
+    };
+    // a swap entry without a swap area can only come from a corrupted table
+    let area = match swap.as_mut() {
+        Some(area) => area,
+        None => return Some(Err(FaultError::SwapIn)),
+    };
+    let frame: PhysFrame = match memory.frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return Some(Err(FaultError::OutOfMemory)),
+    };
+    let contents = unsafe { frame_contents(memory.mapper.phys_offset(), frame) };
+    if area.device.read_blocks(slot * BLOCKS_PER_SLOT, contents).is_err() {
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+        return Some(Err(FaultError::SwapIn));
+    }
+
+    entry.set_addr(frame.start_address(), (flags - SWAPPED) | PageTableFlags::PRESENT);
+    tlb::flush(page.start_address());
+    area.free_slot(slot);
+    Some(Ok(()))
+}
+
+/// Free the swap slot of `page` and clear its entry, if it was swapped out.
+pub(crate) fn discard(memory: &mut KernelMemory, page: Page) {
+    let entry = match unsafe { entry_mut(&mut memory.mapper, page) } {
+        Some(entry) => entry,
+        None => return,
+    };
+    let flags = entry.flags();
+    if flags.contains(PageTableFlags::PRESENT) || !flags.contains(SWAPPED) {
+        return;
+    }
+    let slot = entry.addr().as_u64() / PAGE_SIZE;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SWAP.lock().as_mut().expect("swap entry without a swap area").free_slot(slot)
+    });
+    entry.set_unused();
+}
+
+/// The bytes of `frame`, through the physical memory mapping at `offset`.
+///
+/// This function is unsafe because the caller must guarantee that nothing
+/// else accesses the frame while the slice is in use.
+unsafe fn frame_contents<'a>(offset: VirtAddr, frame: PhysFrame) -> &'a mut [u8] {
+    let start = (offset + frame.start_address().as_u64()).as_mut_ptr::<u8>();
+    core::slice::from_raw_parts_mut(start, PAGE_SIZE as usize)
+}
+
+/// The level 1 entry of `page` in `mapper`, or `None` if a page table on the
+/// way does not exist or `page` is part of a huge page.
+///
+/// This function is unsafe because the caller must guarantee that the entry
+/// is not accessed through `mapper` while the returned reference is in use.
+unsafe fn entry_mut<'a>(mapper: &mut OffsetPageTable, page: Page) -> Option<&'a mut PageTableEntry> {
+    let offset = mapper.phys_offset();
+    let mut table = &mut *(mapper.level_4_table() as *mut PageTable);
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = &mut *(offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>();
+    }
+    Some(&mut table[page.p1_index()])
+}
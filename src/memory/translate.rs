@@ -0,0 +1,220 @@
+use core::fmt;
+
+use x86_64::{
+    structures::paging::{
+        mapper::MappedFrame, OffsetPageTable, PageTable, PageTableFlags, PageTableIndex,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, PageSize,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::serial_println;
+
+/// The result of translating a virtual address with `translate`.
+#[derive(Debug)]
+pub struct Translation {
+    /// The physical address the virtual address maps to.
+    pub phys_addr: PhysAddr,
+    /// The mapped frame, which tells the page size as well.
+    pub frame: MappedFrame,
+    /// The effective flags of the mapping.
+    ///
+    /// `WRITABLE` and `USER_ACCESSIBLE` are only set if they are set on every
+    /// level of the hierarchy, `NO_EXECUTE` is set if it is set on any level.
+    /// All other flags are the ones of the lowest-level entry.
+    pub flags: PageTableFlags,
+}
+
+impl Translation {
+    pub fn is_writable(&self) -> bool {
+        self.flags.contains(PageTableFlags::WRITABLE)
+    }
+
+    pub fn is_user_accessible(&self) -> bool {
+        self.flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        !self.flags.contains(PageTableFlags::NO_EXECUTE)
+    }
+}
+
+/// A leaf mapping found by `for_each_mapping`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub phys_start: PhysAddr,
+    /// 4 KiB, 2 MiB or 1 GiB.
+    pub size: u64,
+    /// Effective flags, as in `Translation::flags`.
+    pub flags: PageTableFlags,
+}
+
+/// Flags that must be set on every level to take effect.
+const AND_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+/// Combine the effective flags of the upper levels with the flags of the next entry.
+fn combine(upper: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let and = upper & entry & AND_FLAGS;
+    let nx = (upper | entry) & PageTableFlags::NO_EXECUTE;
+    (entry - AND_FLAGS - PageTableFlags::NO_EXECUTE) | and | nx
+}
+
+/// Returns the page table stored in `frame`.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped at `physical_memory_offset` and that
+/// `frame` really holds a page table.
+unsafe fn table_at<'a>(frame: PhysFrame, physical_memory_offset: VirtAddr) -> &'a PageTable {
+    &*(physical_memory_offset + frame.start_address().as_u64()).as_ptr()
+}
+
+/// Translates the given virtual address through the page tables of `mapper`.
+///
+/// Unlike `Translate::translate`, the returned flags are the effective ones of
+/// the whole hierarchy. Huge pages of both sizes are supported. Returns `None`
+/// if the address is not mapped.
+pub fn translate(mapper: &mut OffsetPageTable, addr: VirtAddr) -> Option<Translation> {
+    let physical_memory_offset = mapper.phys_offset();
+    let p4 = &*mapper.level_4_table();
+
+    let entry = &p4[addr.p4_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    let flags = entry.flags();
+
+    let p3 = unsafe { table_at(entry.frame().ok()?, physical_memory_offset) };
+    let entry = &p3[addr.p3_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    let flags = combine(flags, entry.flags());
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        let frame = PhysFrame::<Size1GiB>::containing_address(entry.addr());
+        let phys_addr = frame.start_address() + (addr.as_u64() & (Size1GiB::SIZE - 1));
+        return Some(Translation { phys_addr, frame: MappedFrame::Size1GiB(frame), flags });
+    }
+
+    let p2 = unsafe { table_at(entry.frame().ok()?, physical_memory_offset) };
+    let entry = &p2[addr.p2_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    let flags = combine(flags, entry.flags());
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        let frame = PhysFrame::<Size2MiB>::containing_address(entry.addr());
+        let phys_addr = frame.start_address() + (addr.as_u64() & (Size2MiB::SIZE - 1));
+        return Some(Translation { phys_addr, frame: MappedFrame::Size2MiB(frame), flags });
+    }
+
+    let p1 = unsafe { table_at(entry.frame().ok()?, physical_memory_offset) };
+    let entry = &p1[addr.p1_index()];
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return None;
+    }
+    let flags = combine(flags, entry.flags());
+    let frame = PhysFrame::<Size4KiB>::containing_address(entry.addr());
+    let phys_addr = frame.start_address() + u64::from(addr.page_offset());
+    Some(Translation { phys_addr, frame: MappedFrame::Size4KiB(frame), flags })
+}
+
+/// Call `f` for every present leaf mapping of `mapper`, in address order.
+pub fn for_each_mapping(mapper: &mut OffsetPageTable, mut f: impl FnMut(Mapping)) {
+    let physical_memory_offset = mapper.phys_offset();
+    let p4 = &*mapper.level_4_table();
+    walk_table(p4, 4, 0, PageTableFlags::all(), physical_memory_offset, &mut f);
+}
+
+fn walk_table(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    upper_flags: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+    f: &mut impl FnMut(Mapping),
+) {
+    // each entry of a level n table covers 4 KiB * 512^(n-1)
+    let entry_size = Size4KiB::SIZE << (9 * (level as u64 - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let flags = combine(upper_flags, entry.flags());
+        // sign extend, so that the upper half gets canonical addresses
+        let start = VirtAddr::new_truncate(base + index as u64 * entry_size);
+
+        if level == 1 || (level < 4 && entry.flags().contains(PageTableFlags::HUGE_PAGE)) {
+            f(Mapping { start, phys_start: entry.addr(), size: entry_size, flags });
+        } else {
+            let next = unsafe { table_at(PhysFrame::containing_address(entry.addr()), physical_memory_offset) };
+            walk_table(next, level - 1, start.as_u64(), flags, physical_memory_offset, f);
+        }
+    }
+}
+
+/// Compact `rwxug` style rendering of page table flags.
+pub struct FlagsDisplay(pub PageTableFlags);
+
+impl fmt::Display for FlagsDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = self.0;
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(f, "r{}{}{}{}{}",
+            flag(flags.contains(PageTableFlags::WRITABLE), 'w'),
+            flag(!flags.contains(PageTableFlags::NO_EXECUTE), 'x'),
+            flag(flags.contains(PageTableFlags::USER_ACCESSIBLE), 'u'),
+            flag(flags.contains(PageTableFlags::GLOBAL), 'g'),
+            flag(flags.contains(PageTableFlags::NO_CACHE), 'c'),
+        )
+    }
+}
+
+/// Print the page table hierarchy of `mapper` to serial.
+///
+/// Every used level 4 entry is printed with the mappings below it. Adjacent
+/// mappings of the same page size that are physically contiguous and have the
+/// same flags are merged into one line, so the physical memory mapping stays
+/// readable.
+pub fn print_page_tables(mapper: &mut OffsetPageTable) {
+    // a run of merged mappings and the page size of its mappings
+    let mut run: Option<(Mapping, u64)> = None;
+    let mut current_p4: Option<PageTableIndex> = None;
+
+    let flush = |(run, page_size): (Mapping, u64)| {
+        let size_name = match page_size {
+            Size1GiB::SIZE => "1GiB",
+            Size2MiB::SIZE => "2MiB",
+            _ => "4KiB",
+        };
+        serial_println!("    {:#018x}-{:#018x} -> {:#014x} {} ({} x {})",
+            run.start.as_u64(), run.start.as_u64() + run.size, run.phys_start.as_u64(),
+            FlagsDisplay(run.flags), run.size / page_size, size_name);
+    };
+
+    for_each_mapping(mapper, |mapping| {
+        if current_p4 != Some(mapping.start.p4_index()) {
+            if let Some(run) = run.take() {
+                flush(run);
+            }
+            current_p4 = Some(mapping.start.p4_index());
+            serial_println!("P4[{}]:", u16::from(mapping.start.p4_index()));
+        }
+
+        match &mut run {
+            Some((run, page_size)) if *page_size == mapping.size
+                && run.start + run.size == mapping.start
+                && run.phys_start + run.size == mapping.phys_start
+                && run.flags == mapping.flags => run.size += mapping.size,
+            _ => {
+                if let Some(run) = run.replace((mapping, mapping.size)) {
+                    flush(run);
+                }
+            }
+        }
+    });
+    if let Some(run) = run {
+        flush(run);
+    }
+}
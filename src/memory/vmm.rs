@@ -0,0 +1,381 @@
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, MapToError, UnmapError}, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{swap, translate, with_kernel_memory, BuddyFrameAllocator, KernelMemory};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+// the number of ranges that can be reserved in one region at the same time
+const MAX_RESERVATIONS: usize = 128;
+// the number of lazily backed ranges that can be registered at the same time
+const MAX_LAZY_RANGES: usize = 64;
+
+/// The regions of the kernel virtual address space.
+///
+/// Each region has a fixed place in the higher part of the lower half, far
+/// away from the kernel image and the physical memory mapping set up by the
+/// bootloader:
+///
+/// | region   | start                 | size    |
+/// |----------|-----------------------|---------|
+/// | `Heap`   | `0x4242_4242_0000`    | 1 GiB   |
+/// | `Stacks` | `0x5000_0000_0000`    | 64 GiB  |
+/// | `Mmio`   | `0x6000_0000_0000`    | 64 GiB  |
+/// | `PerCpu` | `0x7000_0000_0000`    | 1 GiB   |
+///
+/// The heap region is managed by `allocator` and can not be reserved from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Heap,
+    Stacks,
+    Mmio,
+    PerCpu,
+}
+
+impl Region {
+    const RESERVABLE: [Region; 3] = [Region::Stacks, Region::Mmio, Region::PerCpu];
+
+    pub const fn start(self) -> VirtAddr {
+        VirtAddr::new_truncate(match self {
+            Region::Heap => crate::allocator::HEAP_START as u64,
+            Region::Stacks => 0x5000_0000_0000,
+            Region::Mmio => 0x6000_0000_0000,
+            Region::PerCpu => 0x7000_0000_0000,
+        })
+    }
+
+    pub const fn size(self) -> u64 {
+        match self {
+            Region::Heap => 1 << 30,
+            Region::Stacks => 64 << 30,
+            Region::Mmio => 64 << 30,
+            Region::PerCpu => 1 << 30,
+        }
+    }
+
+    pub fn end(self) -> VirtAddr {
+        self.start() + self.size()
+    }
+
+    pub fn contains(self, addr: VirtAddr) -> bool {
+        self.start() <= addr && addr < self.end()
+    }
+
+    fn table_index(self) -> usize {
+        Self::RESERVABLE.iter().position(|&r| r == self)
+            .expect("the heap region can not be reserved from")
+    }
+}
+
+/// A page aligned range of kernel virtual addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    pub start: VirtAddr,
+    pub size: u64,
+}
+
+impl VirtRange {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// The pages covered by the range.
+    pub fn pages(&self) -> impl Iterator<Item = Page> {
+        self.pages_of::<Size4KiB>()
+    }
+
+    /// The pages of size `S` covered by the range, which must be aligned to `S`.
+    pub fn pages_of<S: PageSize>(&self) -> impl Iterator<Item = Page<S>> {
+        assert!(self.start.is_aligned(S::SIZE) && self.size % S::SIZE == 0,
+            "{:?} is not aligned to {}", self, S::SIZE_AS_DEBUG_STR);
+        let start = Page::containing_address(self.start);
+        Page::range(start, start + self.size / S::SIZE)
+    }
+}
+
+/// The ranges reserved in one region.
+struct Reservations {
+    ranges: [Option<VirtRange>; MAX_RESERVATIONS],
+}
+
+impl Reservations {
+    const fn new() -> Self {
+        Reservations { ranges: [None; MAX_RESERVATIONS] }
+    }
+
+    fn overlaps(&self, start: VirtAddr, size: u64) -> bool {
+        self.ranges.iter().flatten()
+            .any(|r| start < r.end() && r.start < start + size)
+    }
+
+    fn insert(&mut self, range: VirtRange) -> Option<VirtRange> {
+        let slot = self.ranges.iter_mut().find(|r| r.is_none())?;
+        *slot = Some(range);
+        Some(range)
+    }
+}
+
+static RESERVATIONS: Mutex<[Reservations; Region::RESERVABLE.len()]> =
+    Mutex::new([Reservations::new(), Reservations::new(), Reservations::new()]);
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Reserve `size` bytes anywhere in `region`.
+///
+/// The size is rounded up to whole pages. Returns `None` if the region has
+/// no large enough gap left.
+pub fn reserve(region: Region, size: u64) -> Option<VirtRange> {
+    reserve_aligned(region, size, PAGE_SIZE)
+}
+
+/// Reserve `size` bytes in `region`, starting at a multiple of `align`.
+///
+/// `align` must be a power of two of at least the page size.
+pub fn reserve_aligned(region: Region, size: u64, align: u64) -> Option<VirtRange> {
+    assert!(align.is_power_of_two() && align >= PAGE_SIZE, "invalid alignment {:#x}", align);
+    let size = align_up(size.max(1), PAGE_SIZE);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut tables = RESERVATIONS.lock();
+        let table = &mut tables[region.table_index()];
+
+        // the lowest fitting address is either the region start or
+        // directly behind one of the existing reservations
+        let candidates = core::iter::once(region.start())
+            .chain(table.ranges.iter().flatten().map(|r| r.end()));
+        let start = candidates
+            .map(|addr| VirtAddr::new(align_up(addr.as_u64(), align)))
+            .filter(|&start| start + size <= region.end() && !table.overlaps(start, size))
+            .min()?;
+
+        table.insert(VirtRange { start, size })
+    })
+}
+
+/// Reserve the given range, failing if it overlaps an existing reservation.
+pub fn reserve_at(region: Region, start: VirtAddr, size: u64) -> Option<VirtRange> {
+    assert!(start.is_aligned(PAGE_SIZE), "{:?} is not page aligned", start);
+    let size = align_up(size.max(1), PAGE_SIZE);
+    if !region.contains(start) || start + size > region.end() {
+        return None;
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut tables = RESERVATIONS.lock();
+        let table = &mut tables[region.table_index()];
+        if table.overlaps(start, size) {
+            return None;
+        }
+        table.insert(VirtRange { start, size })
+    })
+}
+
+/// Give a reservation back. The range must not be mapped anymore.
+pub fn release(range: VirtRange) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut tables = RESERVATIONS.lock();
+        let region = Region::RESERVABLE.iter()
+            .find(|r| r.contains(range.start))
+            .expect("range is not part of a reservable region");
+        let slot = tables[region.table_index()].ranges.iter_mut()
+            .find(|r| **r == Some(range))
+            .expect("range was not reserved");
+        *slot = None;
+    })
+}
+
+/// Back every page of `range` with a newly allocated frame.
+///
+/// `PRESENT` and `NO_EXECUTE` are added to `flags`. If a frame or page table
+/// can not be allocated, the pages mapped so far are unmapped and freed again.
+pub fn map_range(range: &VirtRange, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    map_range_sized::<Size4KiB>(range, flags)
+}
+
+/// Like `map_range`, but with pages of size `S`. The range must be aligned
+/// to `S`. Panics if the CPU does not support pages of size `S`.
+pub fn map_range_sized<S: PageSize>(range: &VirtRange, flags: PageTableFlags) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    BuddyFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    assert!(page_size_supported::<S>(), "the CPU does not support {} pages", S::SIZE_AS_DEBUG_STR);
+    with_kernel_memory(|memory| {
+        for (mapped, page) in range.pages_of::<S>().enumerate() {
+            if let Err(err) = map_page(memory, page, flags) {
+                let start = Page::<S>::containing_address(range.start);
+                for page in Page::range(start, start + mapped as u64) {
+                    let _ = unmap_page(memory, page);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Whether the CPU can map pages of size `S`; 1 GiB pages need `pdpe1gb`.
+pub fn page_size_supported<S: PageSize>() -> bool {
+    S::SIZE != Size1GiB::SIZE
+        || (crate::cpuid(0x8000_0000).eax >= 0x8000_0001 && crate::cpuid(0x8000_0001).edx & (1 << 26) != 0)
+}
+
+/// A range whose pages are only backed by frames once they are touched.
+#[derive(Debug, Clone, Copy)]
+struct LazyRange {
+    range: VirtRange,
+    flags: PageTableFlags,
+    // whether `swap::reclaim` may page the range out again
+    swappable: bool,
+}
+
+static LAZY_RANGES: Mutex<[Option<LazyRange>; MAX_LAZY_RANGES]> = Mutex::new([None; MAX_LAZY_RANGES]);
+
+/// Back the pages of `range` on demand instead of right away.
+///
+/// The first access to a page raises a page fault, which maps a zeroed
+/// frame with `flags` the same way `map_range` would. Returns `false` if
+/// too many ranges are registered already. `unmap_range` frees the pages
+/// that were touched and forgets the registration.
+pub fn map_lazy(range: &VirtRange, flags: PageTableFlags) -> bool {
+    register_lazy(range, flags, false)
+}
+
+/// Like `map_lazy`, but the pages may also be written to swap and unmapped
+/// again by `swap::reclaim` when memory runs low.
+///
+/// A swapped out page is read back by the page fault handler, so the range
+/// must not be touched while holding a memory management lock, and must
+/// not hold page tables, stacks or anything else the CPU accesses without
+/// going through a fault.
+pub fn map_swappable(range: &VirtRange, flags: PageTableFlags) -> bool {
+    register_lazy(range, flags, true)
+}
+
+fn register_lazy(range: &VirtRange, flags: PageTableFlags, swappable: bool) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut ranges = LAZY_RANGES.lock();
+        match ranges.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(LazyRange { range: *range, flags, swappable });
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// The flags the page containing `addr` is mapped with on first touch, or
+/// `None` if `addr` is not part of a lazily backed range.
+///
+/// Called from the page fault handler, so it gives up instead of spinning
+/// if the table is locked by the interrupted code.
+pub(crate) fn lazy_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let ranges = LAZY_RANGES.try_lock()?;
+    ranges.iter().flatten()
+        .find(|r| r.range.contains(addr))
+        .map(|r| r.flags)
+}
+
+/// The ranges registered with `map_swappable`.
+pub(crate) fn swappable_ranges() -> [Option<VirtRange>; MAX_LAZY_RANGES] {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        LAZY_RANGES.lock().map(|r| r.filter(|r| r.swappable).map(|r| r.range))
+    })
+}
+
+/// Unmap every mapped page of `range` and free its frame.
+///
+/// Huge pages are unmapped as a whole and must lie completely inside the
+/// range. Pages that are not mapped, like guard pages or untouched lazily
+/// backed pages, are skipped, and the swap slots of swapped out pages are
+/// freed.
+pub fn unmap_range(range: &VirtRange) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for slot in LAZY_RANGES.lock().iter_mut() {
+            if matches!(slot, Some(lazy) if range.contains(lazy.range.start)) {
+                *slot = None;
+            }
+        }
+    });
+    with_kernel_memory(|memory| {
+        let mut addr = range.start;
+        while addr < range.end() {
+            let size = match translate(&mut memory.mapper, addr).map(|t| t.frame) {
+                None => {
+                    swap::discard(memory, Page::containing_address(addr));
+                    PAGE_SIZE
+                }
+                Some(MappedFrame::Size4KiB(_)) => unmap_in_range::<Size4KiB>(memory, range, addr),
+                Some(MappedFrame::Size2MiB(_)) => unmap_in_range::<Size2MiB>(memory, range, addr),
+                Some(MappedFrame::Size1GiB(_)) => unmap_in_range::<Size1GiB>(memory, range, addr),
+            };
+            addr += size;
+        }
+    })
+}
+
+// unmap the page of size `S` at `addr`, returning its size
+fn unmap_in_range<S: PageSize>(memory: &mut KernelMemory, range: &VirtRange, addr: VirtAddr) -> u64
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    BuddyFrameAllocator: FrameDeallocator<S>,
+{
+    assert!(addr.is_aligned(S::SIZE) && addr + S::SIZE <= range.end(),
+        "{} page at {:?} is not completely inside {:?}", S::SIZE_AS_DEBUG_STR, addr, range);
+    if let Err(err) = unmap_page(memory, Page::<S>::containing_address(addr)) {
+        panic!("failed to unmap {:?}: {:?}", addr, err);
+    }
+    S::SIZE
+}
+
+/// Map `page` to a new frame, or block of frames for huge pages.
+///
+/// The page is always mapped no-execute, code only lives in the kernel image.
+pub(crate) fn map_page<S: PageSize>(memory: &mut KernelMemory, page: Page<S>, flags: PageTableFlags) -> Result<(), MapToError<S>>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    BuddyFrameAllocator: FrameAllocator<S> + FrameDeallocator<S>,
+{
+    let frame = FrameAllocator::<S>::allocate_frame(&mut memory.frame_allocator)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let result = unsafe {
+        memory.mapper.map_to(page, frame, flags | PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE, &mut memory.frame_allocator)
+    };
+    match result {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
+
+/// Unmap `page` and free the frame it was mapped to, unless it is still shared.
+pub(crate) fn unmap_page<S: PageSize>(memory: &mut KernelMemory, page: Page<S>) -> Result<(), UnmapError>
+where
+    for<'a> OffsetPageTable<'a>: Mapper<S>,
+    BuddyFrameAllocator: FrameDeallocator<S>,
+{
+    let (frame, flush) = memory.mapper.unmap(page)?;
+    flush.flush();
+    // a copy-on-write frame may still be mapped elsewhere
+    if super::cow::release_frame(frame.start_address()) {
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+use core::fmt;
+
+use x86_64::PhysAddr;
+
+use super::report::Size;
+
+/// Physical memory zones, for devices that can only address part of memory.
+///
+/// | zone     | range           | used by                          |
+/// |----------|-----------------|----------------------------------|
+/// | `Dma`    | below 16 MiB    | ISA DMA                          |
+/// | `Dma32`  | 16 MiB to 4 GiB | PCI devices with 32-bit DMA      |
+/// | `Normal` | above 4 GiB     | everything else                  |
+///
+/// A request for a zone may be served from a lower zone as well, since
+/// lower memory satisfies the same address limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Zone {
+    Dma,
+    Dma32,
+    Normal,
+}
+
+impl Zone {
+    /// All zones, from the lowest to the highest.
+    pub const ALL: [Zone; 3] = [Zone::Dma, Zone::Dma32, Zone::Normal];
+
+    pub const fn start(self) -> PhysAddr {
+        PhysAddr::new_truncate(match self {
+            Zone::Dma => 0,
+            Zone::Dma32 => 16 << 20,
+            Zone::Normal => 4 << 30,
+        })
+    }
+
+    /// The end of the zone, exclusive. `Normal` extends to the end of memory.
+    pub const fn end(self) -> PhysAddr {
+        PhysAddr::new_truncate(match self {
+            Zone::Dma => 16 << 20,
+            Zone::Dma32 => 4 << 30,
+            Zone::Normal => u64::MAX,
+        })
+    }
+
+    /// The zone the given physical address belongs to.
+    pub fn containing(addr: PhysAddr) -> Zone {
+        if addr < Zone::Dma.end() {
+            Zone::Dma
+        } else if addr < Zone::Dma32.end() {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    pub(super) fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Frame usage of one zone, as reported by `BuddyFrameAllocator::zone_stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneStats {
+    pub zone: Zone,
+    pub total_frames: usize,
+    pub free_frames: usize,
+}
+
+impl fmt::Display for ZoneStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {} free of {} frames ({} free)",
+            self.zone, self.free_frames, self.total_frames, Size(self.free_frames as u64 * 4096))
+    }
+}
@@ -1,24 +1,54 @@
 use x86_64::instructions::port::Port;
 
+/// Frequency of the clock that drives the PIT, in Hz.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
 
+/// Channel 0 of the 8254 programmable interval timer, which raises IRQ 0.
 pub struct PIT {
-    interval: u16,
+    divisor: u16,
     cmd_port: Port<u8>,
     channel0: Port<u8>,
 }
 
 impl PIT {
-    pub fn new(int: u16) -> PIT{
-        PIT{interval: int, cmd_port: Port::new(0x43), channel0: Port::new(0x40)}
+    /// A PIT that fires as close to `frequency` times a second as the
+    /// 16 bit divisor allows, i.e. between 19 Hz and `PIT_FREQUENCY`.
+    pub fn new(frequency: u32) -> PIT{
+        let div = (PIT_FREQUENCY + frequency / 2) / frequency.max(1);
+        PIT{divisor: div.clamp(1, u16::MAX as u32) as u16, cmd_port: Port::new(0x43), channel0: Port::new(0x40)}
     }
 
+    /// The frequency the PIT really fires at, rounded to Hz.
+    pub fn frequency(&self) -> u32 {
+        (PIT_FREQUENCY + self.divisor as u32 / 2) / self.divisor as u32
+    }
+
+    /// The length of a period in nanoseconds.
+    pub fn period_nanos(&self) -> u64 {
+        self.divisor as u64 * 1_000_000_000 / PIT_FREQUENCY as u64
+    }
+
+    /// Program channel 0 to fire with the divisor.
+    ///
+    /// This function is unsafe because it reprograms the timer interrupt for
+    /// the whole system.
     pub unsafe fn init(&mut self){
+       // channel 0, low byte then high byte, mode 3 (square wave), binary
        self.cmd_port.write(0x36);
-       let div = 1193182 / (self.interval as u32);
-       let low_bits = (div & 0xff) as u8;
+       let low_bits = (self.divisor & 0xff) as u8;
+       // the write to the POST port gives the PIT a moment between the two bytes
        Port::<u8>::new(0x80).write(0x80);
-       let high_bits = ((div >>8)&0xff) as u8;
+       let high_bits = (self.divisor >> 8) as u8;
        self.channel0.write(low_bits);
        self.channel0.write(high_bits);
     }
-}
\ No newline at end of file
+
+    /// Stop channel 0, so that it raises no interrupts until `init` is called again.
+    ///
+    /// This function is unsafe because it stops the timer interrupt for the
+    /// whole system.
+    pub unsafe fn stop() {
+        // mode 0 (interrupt on terminal count) does not start before a count is written
+        Port::<u8>::new(0x43).write(0x30);
+    }
+}
@@ -0,0 +1,251 @@
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::interrupts::{Controller, PICS};
+
+// CMOS registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+const A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const B_24_HOUR: u8 = 1 << 1;
+const B_BINARY: u8 = 1 << 2;
+const B_PERIODIC: u8 = 1 << 6;
+const C_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+/// Frequency of the RTC oscillator, which the periodic rates are divided from.
+pub const BASE_FREQUENCY: u32 = 32_768;
+
+/// Why the RTC could not be set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// The periodic interrupt only runs at powers of two from 2 to 8192 Hz.
+    UnsupportedFrequency(u32),
+    /// The RTC holds a date that does not exist or lies before 1970, e.g.
+    /// after its battery ran out.
+    InvalidDate(DateTime),
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtcError::UnsupportedFrequency(frequency) => write!(f, "the RTC cannot fire at {} Hz", frequency),
+            RtcError::InvalidDate(date_time) => write!(f, "the RTC holds the invalid date {}", date_time),
+        }
+    }
+}
+
+/// A calendar date and time, in UTC like the RTC is assumed to keep it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Whether every field is in its range and the date is not before 1970.
+    ///
+    /// Days past the end of a shorter month are accepted.
+    pub fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && (1..=12).contains(&self.month)
+            && (1..=31).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    /// The seconds since 1970-01-01 00:00:00, for valid dates, see `is_valid`.
+    pub fn to_unix_secs(&self) -> u64 {
+        // days since 0000-03-01, with the leap day at the end of the year
+        let (month, year) = (self.month as u64, self.year as u64);
+        let year = if month <= 2 { year - 1 } else { year };
+        let (era, year_of_era) = (year / 400, year % 400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        days * 86_400 + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    /// The date and time `secs` seconds after 1970-01-01 00:00:00.
+    pub fn from_unix_secs(secs: u64) -> DateTime {
+        let days = secs / 86_400 + 719_468;
+        let (era, day_of_era) = (days / 146_097, days % 146_097);
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = era * 400 + year_of_era + (month <= 2) as u64;
+        let time = secs % 86_400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// The index and data ports of the CMOS memory the RTC lives in.
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, reg: u8) -> u8 {
+        unsafe {
+            self.index.write(reg);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) {
+        unsafe {
+            self.index.write(reg);
+            self.data.write(value);
+        }
+    }
+
+    /// The time registers and the century register, if there is one, read
+    /// outside of an update.
+    fn read_time(&mut self, century: u8) -> [u8; 7] {
+        // an update takes up to 2ms, during which the registers are inconsistent
+        while self.read(STATUS_A) & A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        let [second, minute, hour, day, month, year] = [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|reg| self.read(reg));
+        let century = if century == 0 { 0 } else { self.read(century) };
+        [second, minute, hour, day, month, year, century]
+    }
+}
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos { index: Port::new(0x70), data: Port::new(0x71) });
+
+// the index written first must not be changed by an interrupt handler before the data access
+fn with_cmos<R>(f: impl FnOnce(&mut Cmos) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut CMOS.lock()))
+}
+
+/// Read the date and time from the RTC.
+///
+/// The century comes from the register the FADT names once `acpi::init`
+/// parsed it, without it the year is assumed to be in the 2000s.
+///
+/// Returns an error if the registers do not hold a valid date.
+pub fn read() -> Result<DateTime, RtcError> {
+    let century = crate::acpi::info().map_or(0, |info| info.fadt.century_register);
+    with_cmos(|cmos| {
+        // an update may still start between the check and the reads, so
+        // read until two passes agree
+        let mut raw = cmos.read_time(century);
+        loop {
+            let again = cmos.read_time(century);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let date_time = decode(raw, cmos.read(STATUS_B));
+        if date_time.is_valid() {
+            Ok(date_time)
+        } else {
+            Err(RtcError::InvalidDate(date_time))
+        }
+    })
+}
+
+/// Convert the raw registers according to the BCD and 12 hour bits of status B.
+fn decode(raw: [u8; 7], status_b: u8) -> DateTime {
+    let value = |byte: u8| if status_b & B_BINARY != 0 { byte } else { (byte >> 4) * 10 + (byte & 0x0f) };
+    let mut hour = value(raw[2] & !HOUR_PM);
+    if status_b & B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM noon
+        hour %= 12;
+        if raw[2] & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    let century = match raw[6] {
+        0 => 20,
+        century => value(century) as u16,
+    };
+    DateTime {
+        year: century * 100 + value(raw[5]) as u16,
+        month: value(raw[4]),
+        day: value(raw[3]),
+        hour,
+        minute: value(raw[1]),
+        second: value(raw[0]),
+    }
+}
+
+/// Make the RTC raise IRQ 8 `frequency` times a second, which must be a
+/// power of two from 2 to 8192.
+///
+/// `time::use_rtc` builds the timer on it, the interrupts are ignored
+/// otherwise.
+pub fn enable_periodic(frequency: u32) -> Result<(), RtcError> {
+    if !frequency.is_power_of_two() || !(2..=8192).contains(&frequency) {
+        return Err(RtcError::UnsupportedFrequency(frequency));
+    }
+    // the frequency is `BASE_FREQUENCY >> (rate - 1)`
+    let rate = 16 - frequency.trailing_zeros() as u8;
+    with_cmos(|cmos| {
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | B_PERIODIC);
+        // a pending interrupt that was never acknowledged would block all further ones
+        cmos.read(STATUS_C);
+    });
+    if crate::interrupts::controller() == Controller::Pic8259 {
+        interrupts::without_interrupts(|| unsafe {
+            let mut pics = PICS.lock();
+            let [primary, secondary] = pics.read_masks();
+            // IRQ 8 arrives through the cascade on IRQ 2
+            pics.write_masks(primary & !(1 << 2), secondary & !1);
+        });
+    }
+    Ok(())
+}
+
+/// Stop the periodic interrupt.
+pub fn disable_periodic() {
+    with_cmos(|cmos| {
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !B_PERIODIC);
+    });
+}
+
+/// Acknowledge an RTC interrupt, returning whether it was a periodic one.
+///
+/// The RTC raises no further interrupts until this is done.
+pub(crate) fn acknowledge_interrupt() -> bool {
+    with_cmos(|cmos| cmos.read(STATUS_C) & C_PERIODIC != 0)
+}
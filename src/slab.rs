@@ -0,0 +1,391 @@
+use core::{fmt, marker::PhantomData, mem, ops::{Deref, DerefMut}, ptr::{self, NonNull}};
+
+use spin::Mutex;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::memory;
+
+// a slab is at most 2^MAX_SLAB_ORDER frames large
+const MAX_SLAB_ORDER: usize = 4;
+// prefer slabs that hold at least this many objects
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+const FRAME_SIZE: usize = 4096;
+/// Maximum number of caches that can be registered at the same time.
+pub const MAX_CACHES: usize = 32;
+
+/// Header at the start of every slab.
+///
+/// It is followed by a stack of free object indices and then the objects themselves.
+struct SlabHeader {
+    next: *mut SlabHeader,
+    frame: PhysFrame,
+    free_count: usize,
+    on_partial_list: bool,
+}
+
+/// Where things live inside a slab of a given cache.
+#[derive(Clone, Copy)]
+struct SlabLayout {
+    order: usize,
+    capacity: usize,
+    objects_offset: usize,
+}
+
+impl SlabLayout {
+    fn new(size: usize, align: usize) -> Self {
+        assert!(size > 0, "slab caches do not support zero sized types");
+        assert!(align <= FRAME_SIZE, "slab objects can not be aligned to more than a frame");
+
+        let mut best = None;
+        for order in 0..=MAX_SLAB_ORDER {
+            let slab_size = FRAME_SIZE << order;
+            let mut capacity = slab_size / size;
+            while capacity > 0 {
+                let objects_offset = align_up(Self::free_stack_offset() + capacity * mem::size_of::<u16>(), align);
+                if objects_offset + capacity * size <= slab_size {
+                    best = Some(SlabLayout { order, capacity, objects_offset });
+                    break;
+                }
+                capacity -= 1;
+            }
+            if capacity >= MIN_OBJECTS_PER_SLAB {
+                break;
+            }
+        }
+        best.expect("object is too large for a slab")
+    }
+
+    fn free_stack_offset() -> usize {
+        align_up(mem::size_of::<SlabHeader>(), mem::align_of::<u16>())
+    }
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Usage statistics of one cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    /// Frames held by the cache, including the slab headers.
+    pub frames: usize,
+    pub slabs: usize,
+    pub objects_in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<16} {:>6} B x {:>4}/slab, {:>4} slabs, {:>6} in use, {} allocs, {} frees",
+            self.name, self.object_size, self.objects_per_slab, self.slabs,
+            self.objects_in_use, self.allocations, self.frees)
+    }
+}
+
+struct CacheInner {
+    layout: Option<SlabLayout>,
+    // slabs with at least one free object
+    partial: *mut SlabHeader,
+    // slabs without free objects
+    full: *mut SlabHeader,
+    slabs: usize,
+    objects_in_use: usize,
+    allocations: usize,
+    frees: usize,
+    registered: bool,
+}
+
+/// A named cache of constructed objects of type `T`.
+///
+/// Objects are carved out of slabs of whole frames taken from the kernel frame
+/// allocator, so the cache does not depend on the global allocator. Every
+/// object is constructed once when its slab is created. A freed object is not
+/// dropped but stays constructed and is handed out again by the next `alloc`,
+/// so users should return objects in a reusable state.
+///
+/// ```ignore
+/// static TASKS: SlabCache<Task> = SlabCache::new("task", Task::new);
+/// let task = TASKS.alloc().unwrap();
+/// ```
+pub struct SlabCache<T> {
+    name: &'static str,
+    constructor: fn() -> T,
+    inner: Mutex<CacheInner>,
+    _marker: PhantomData<T>,
+}
+
+// the raw slab pointers are only touched with the lock held
+unsafe impl<T: Send> Send for SlabCache<T> {}
+unsafe impl<T: Send> Sync for SlabCache<T> {}
+
+impl<T: Send> SlabCache<T> {
+    /// Create an empty cache. No memory is taken until the first `alloc`.
+    pub const fn new(name: &'static str, constructor: fn() -> T) -> Self {
+        SlabCache {
+            name,
+            constructor,
+            inner: Mutex::new(CacheInner {
+                layout: None,
+                partial: ptr::null_mut(),
+                full: ptr::null_mut(),
+                slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                frees: 0,
+                registered: false,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Take an object from the cache, creating a new slab if all are full.
+    ///
+    /// Returns `None` if no frames are left for a new slab, or if the caller
+    /// holds the kernel memory lock and a new slab is needed.
+    pub fn alloc(&'static self) -> Option<SlabBox<T>> {
+        let mut inner = self.inner.lock();
+        if !inner.registered {
+            inner.registered = register(self);
+        }
+        let layout = *inner.layout.get_or_insert_with(|| SlabLayout::new(mem::size_of::<T>(), mem::align_of::<T>()));
+
+        if inner.partial.is_null() {
+            // the constructor may use this cache as well
+            drop(inner);
+            let slab = self.new_slab(layout)?;
+            inner = self.inner.lock();
+            unsafe { (*slab).next = inner.partial };
+            inner.partial = slab;
+            inner.slabs += 1;
+        }
+
+        let slab = inner.partial;
+        let object = unsafe {
+            let header = &mut *slab;
+            header.free_count -= 1;
+            let index = *Self::free_stack(slab).add(header.free_count);
+            if header.free_count == 0 {
+                // the slab is full now, move it over to the full list
+                inner.partial = header.next;
+                header.next = inner.full;
+                header.on_partial_list = false;
+                inner.full = slab;
+            }
+            Self::object(slab, layout, index as usize)
+        };
+
+        inner.objects_in_use += 1;
+        inner.allocations += 1;
+        Some(SlabBox { cache: self, slab, object })
+    }
+
+    /// Give all frames of completely unused slabs back to the frame allocator.
+    ///
+    /// The cached objects in those slabs are dropped. Returns the number of
+    /// frames that were freed.
+    pub fn shrink(&self) -> usize {
+        let mut inner = self.inner.lock();
+        let layout = match inner.layout {
+            Some(layout) => layout,
+            None => return 0,
+        };
+
+        // unlink the unused slabs first, their objects are dropped without
+        // the lock, since a destructor may use this cache as well
+        let mut unused: *mut SlabHeader = ptr::null_mut();
+        let mut link: *mut *mut SlabHeader = &mut inner.partial;
+        unsafe {
+            while !(*link).is_null() {
+                let slab = *link;
+                if (*slab).free_count == layout.capacity {
+                    *link = (*slab).next;
+                    (*slab).next = unused;
+                    unused = slab;
+                    inner.slabs -= 1;
+                } else {
+                    link = &mut (*slab).next;
+                }
+            }
+        }
+        drop(inner);
+
+        let mut freed = 0;
+        while !unused.is_null() {
+            unsafe {
+                let slab = unused;
+                unused = (*slab).next;
+                self.free_slab(slab, layout);
+            }
+            freed += 1 << layout.order;
+        }
+        freed
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        let layout = inner.layout.unwrap_or_else(|| SlabLayout::new(mem::size_of::<T>(), mem::align_of::<T>()));
+        CacheStats {
+            name: self.name,
+            object_size: mem::size_of::<T>(),
+            objects_per_slab: layout.capacity,
+            frames: inner.slabs << layout.order,
+            slabs: inner.slabs,
+            objects_in_use: inner.objects_in_use,
+            allocations: inner.allocations,
+            frees: inner.frees,
+        }
+    }
+
+    fn free(&self, slab: *mut SlabHeader, object: NonNull<T>) {
+        let mut inner = self.inner.lock();
+        let layout = inner.layout.expect("object freed to a cache without slabs");
+        unsafe {
+            let header = &mut *slab;
+            let objects = Self::object(slab, layout, 0).as_ptr();
+            let index = object.as_ptr().offset_from(objects) as u16;
+            *Self::free_stack(slab).add(header.free_count) = index;
+            header.free_count += 1;
+
+            if !header.on_partial_list {
+                // unlink from the full list and make the slab available again
+                let mut link: *mut *mut SlabHeader = &mut inner.full;
+                while *link != slab {
+                    link = &mut (**link).next;
+                }
+                *link = header.next;
+                header.next = inner.partial;
+                header.on_partial_list = true;
+                inner.partial = slab;
+            }
+        }
+        inner.objects_in_use -= 1;
+        inner.frees += 1;
+    }
+
+    /// Allocate and construct a new slab, returning its header.
+    fn new_slab(&self, layout: SlabLayout) -> Option<*mut SlabHeader> {
+        let (frame, offset) = memory::try_with_kernel_memory(|memory| {
+            let frame = memory.frame_allocator.allocate_frames(layout.order);
+            (frame, memory.mapper.phys_offset())
+        })?;
+        let frame = frame?;
+        let slab = (offset + frame.start_address().as_u64()).as_mut_ptr::<SlabHeader>();
+
+        unsafe {
+            slab.write(SlabHeader { next: ptr::null_mut(), frame, free_count: layout.capacity, on_partial_list: true });
+            for index in 0..layout.capacity {
+                // hand out low indices first
+                *Self::free_stack(slab).add(index) = (layout.capacity - 1 - index) as u16;
+                Self::object(slab, layout, index).as_ptr().write((self.constructor)());
+            }
+        }
+        Some(slab)
+    }
+
+    unsafe fn free_slab(&self, slab: *mut SlabHeader, layout: SlabLayout) {
+        for index in 0..layout.capacity {
+            ptr::drop_in_place(Self::object(slab, layout, index).as_ptr());
+        }
+        let frame = (*slab).frame;
+        memory::with_kernel_memory(|memory| {
+            memory.frame_allocator.deallocate_frames(frame, layout.order);
+        });
+    }
+
+    unsafe fn free_stack(slab: *mut SlabHeader) -> *mut u16 {
+        (slab as *mut u8).add(SlabLayout::free_stack_offset()) as *mut u16
+    }
+
+    unsafe fn object(slab: *mut SlabHeader, layout: SlabLayout, index: usize) -> NonNull<T> {
+        let first = (slab as *mut u8).add(layout.objects_offset) as *mut T;
+        NonNull::new_unchecked(first.add(index))
+    }
+}
+
+/// An object borrowed from a `SlabCache`, returned to it on drop.
+pub struct SlabBox<T: Send + 'static> {
+    cache: &'static SlabCache<T>,
+    slab: *mut SlabHeader,
+    object: NonNull<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Send + Sync> Sync for SlabBox<T> {}
+
+impl<T: Send> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T: Send> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T: Send> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        self.cache.free(self.slab, self.object);
+    }
+}
+
+impl<T: Send + fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// Type-erased view of a cache, used by the registry.
+pub trait Cache: Sync {
+    fn stats(&self) -> CacheStats;
+    fn shrink(&self) -> usize;
+}
+
+impl<T: Send> Cache for SlabCache<T> {
+    fn stats(&self) -> CacheStats {
+        SlabCache::stats(self)
+    }
+
+    fn shrink(&self) -> usize {
+        SlabCache::shrink(self)
+    }
+}
+
+static CACHES: Mutex<[Option<&'static dyn Cache>; MAX_CACHES]> = Mutex::new([None; MAX_CACHES]);
+
+/// Add a cache to the registry, returns false if the registry is full.
+fn register(cache: &'static dyn Cache) -> bool {
+    let mut caches = CACHES.lock();
+    match caches.iter_mut().find(|c| c.is_none()) {
+        Some(slot) => {
+            *slot = Some(cache);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Call `f` with the statistics of every cache that has been used so far.
+pub fn for_each_cache(mut f: impl FnMut(CacheStats)) {
+    let caches = *CACHES.lock();
+    for cache in caches.iter().flatten() {
+        f(cache.stats());
+    }
+}
+
+/// Shrink every registered cache, returning the number of frames freed.
+pub fn shrink_all() -> usize {
+    let caches = *CACHES.lock();
+    caches.iter().flatten().map(|cache| cache.shrink()).sum()
+}
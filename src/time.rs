@@ -0,0 +1,242 @@
+use core::{fmt, sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering}, time::Duration};
+
+use crate::{hpet, pit_8254::PIT, rtc::{self, RtcError}};
+
+pub mod tsc;
+pub mod wall;
+
+pub use wall::{SystemTime, UNIX_EPOCH};
+
+/// The timer frequency `init` programs the PIT with, in Hz.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// time since boot at the last tick, so that changing the frequency keeps it monotonic
+static UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+static TICK_NANOS: AtomicU64 = AtomicU64::new(0);
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static TICK_SOURCE: AtomicU8 = AtomicU8::new(TickSource::Pit as u8);
+
+/// The device whose interrupt drives the tick count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickSource {
+    Pit = 0,
+    /// The periodic interrupt of the CMOS real-time clock.
+    Rtc = 1,
+}
+
+/// Program the PIT to raise a timer interrupt `frequency` times a second.
+///
+/// Can be called again to change the rate, the tick count and the uptime
+/// keep counting from where they are. Switches back from the RTC if
+/// `use_rtc` was called.
+pub fn set_frequency(frequency: u32) {
+    let mut pit = PIT::new(frequency);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if tick_source() == TickSource::Rtc {
+            rtc::disable_periodic();
+        }
+        TICK_NANOS.store(pit.period_nanos(), Ordering::Relaxed);
+        FREQUENCY.store(pit.frequency(), Ordering::Relaxed);
+        TICK_SOURCE.store(TickSource::Pit as u8, Ordering::Relaxed);
+        unsafe { pit.init() };
+    });
+}
+
+/// Drive the ticks from the periodic interrupt of the RTC instead of the
+/// PIT, `frequency` times a second, which must be a power of two from 2 to
+/// 8192. The PIT is stopped.
+pub fn use_rtc(frequency: u32) -> Result<(), RtcError> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        rtc::enable_periodic(frequency)?;
+        unsafe { PIT::stop() };
+        TICK_NANOS.store(1_000_000_000 / frequency as u64, Ordering::Relaxed);
+        FREQUENCY.store(frequency, Ordering::Relaxed);
+        TICK_SOURCE.store(TickSource::Rtc as u8, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// The device the ticks come from.
+pub fn tick_source() -> TickSource {
+    match TICK_SOURCE.load(Ordering::Relaxed) {
+        1 => TickSource::Rtc,
+        _ => TickSource::Pit,
+    }
+}
+
+/// The frequency the timer interrupt really fires at, in Hz, or 0 before
+/// `set_frequency` was called.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Called by the timer interrupt handlers, counts only the interrupts of
+/// the current `tick_source`.
+pub(crate) fn tick(source: TickSource) {
+    if source != tick_source() {
+        return;
+    }
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    UPTIME_NANOS.fetch_add(TICK_NANOS.load(Ordering::Relaxed), Ordering::Relaxed);
+}
+
+/// The number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The time since the timer was started, with the resolution of one tick.
+pub fn uptime() -> Duration {
+    Duration::from_nanos(UPTIME_NANOS.load(Ordering::Relaxed))
+}
+
+/// Halt the CPU until at least `duration` has passed.
+///
+/// Panics if interrupts are disabled, since the timer could never wake the
+/// CPU up again.
+pub fn sleep(duration: Duration) {
+    assert!(x86_64::instructions::interrupts::are_enabled(), "sleeping with interrupts disabled");
+    let end = uptime() + duration;
+    while uptime() < end {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Like `sleep`, for `ms` milliseconds.
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
+}
+
+/// The counter `monotonic_now` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// The timer interrupt count, with the resolution of one tick.
+    Pit = 0,
+    /// The main counter of the HPET.
+    Hpet = 1,
+    /// The invariant time stamp counter.
+    Tsc = 2,
+}
+
+impl fmt::Display for ClockSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClockSource::Pit => write!(f, "PIT"),
+            ClockSource::Hpet => write!(f, "HPET"),
+            ClockSource::Tsc => write!(f, "TSC"),
+        }
+    }
+}
+
+// how long the TSC is calibrated for
+const CALIBRATION_NANOS: u64 = 50_000_000;
+
+static SOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+// the uptime and the counter of the source when it was selected
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+static BASE_COUNT: AtomicU64 = AtomicU64::new(0);
+// the latest value `monotonic_now` returned
+static LAST_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Start the HPET, calibrate the TSC against it, or against the PIT if
+/// there is no HPET, and select the most precise clock for `monotonic_now`.
+///
+/// The TSC is only used if it is invariant and the HPET only if its counter
+/// has 64 bits. Must be called after `allocator::init_heap`, with
+/// interrupts enabled.
+pub fn init_clocks() -> ClockSource {
+    let hpet = hpet::init().ok();
+    let calibrated = match hpet {
+        // a 32 bit counter could wrap during the calibration
+        Some(hpet) if hpet.is_64bit() => {
+            tsc::calibrate(|| hpet.ticks_to_nanos(hpet.counter()), CALIBRATION_NANOS);
+            true
+        }
+        _ if x86_64::instructions::interrupts::are_enabled() => {
+            tsc::calibrate(|| UPTIME_NANOS.load(Ordering::Relaxed), CALIBRATION_NANOS);
+            true
+        }
+        _ => false,
+    };
+    let source = if calibrated && tsc::is_invariant() {
+        ClockSource::Tsc
+    } else if matches!(hpet, Some(hpet) if hpet.is_64bit()) {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        BASE_NANOS.store(monotonic_now().as_nanos() as u64, Ordering::Relaxed);
+        BASE_COUNT.store(read_counter(source), Ordering::Relaxed);
+        SOURCE.store(source as u8, Ordering::Relaxed);
+    });
+    source
+}
+
+/// The clock `monotonic_now` reads.
+pub fn clock_source() -> ClockSource {
+    match SOURCE.load(Ordering::Relaxed) {
+        2 => ClockSource::Tsc,
+        1 => ClockSource::Hpet,
+        _ => ClockSource::Pit,
+    }
+}
+
+fn read_counter(source: ClockSource) -> u64 {
+    match source {
+        ClockSource::Tsc => tsc::read(),
+        ClockSource::Hpet => hpet::hpet().unwrap().counter(),
+        ClockSource::Pit => UPTIME_NANOS.load(Ordering::Relaxed),
+    }
+}
+
+/// The time since boot, with nanosecond resolution once `init_clocks`
+/// selected the TSC or the HPET. Never goes backwards.
+pub fn monotonic_now() -> Duration {
+    let source = clock_source();
+    let elapsed = read_counter(source).wrapping_sub(BASE_COUNT.load(Ordering::Relaxed));
+    let elapsed = match source {
+        ClockSource::Tsc => tsc::ticks_to_nanos(elapsed).unwrap(),
+        ClockSource::Hpet => hpet::hpet().unwrap().ticks_to_nanos(elapsed),
+        ClockSource::Pit => elapsed,
+    };
+    let nanos = BASE_NANOS.load(Ordering::Relaxed) + elapsed;
+    // the uptime lags behind the other clocks by up to a tick when switching
+    let last = LAST_NANOS.fetch_max(nanos, Ordering::Relaxed);
+    Duration::from_nanos(nanos.max(last))
+}
+
+/// Run `f` and return its result with the time it took.
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Duration) {
+    let start = monotonic_now();
+    let result = f();
+    (result, monotonic_now() - start)
+}
+
+/// A point in `monotonic_now` time, displayed as seconds with microseconds
+/// like the prefix of `log!` messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub Duration);
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp(monotonic_now())
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>5}.{:06}", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
+/// Print a message prefixed with the time since boot to the serial port.
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        $crate::serial_println!("[{}] {}", $crate::time::Timestamp::now(), format_args!($($arg)*));
+    };
+}
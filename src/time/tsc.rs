@@ -0,0 +1,53 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// calibrated frequency in Hz, 0 until `calibrate` succeeded
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Read the time stamp counter.
+pub fn read() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { core::arch::asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack)) };
+    (high as u64) << 32 | low as u64
+}
+
+/// Whether the TSC ticks at a constant rate in all power states.
+pub fn is_invariant() -> bool {
+    crate::cpuid(0x8000_0000).eax >= 0x8000_0007 && crate::cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// The TSC frequency in Hz, if it has been calibrated.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Convert a number of TSC ticks to nanoseconds, if the TSC has been calibrated.
+pub fn ticks_to_nanos(ticks: u64) -> Option<u64> {
+    frequency().map(|frequency| (ticks as u128 * 1_000_000_000 / frequency as u128) as u64)
+}
+
+/// Measure the TSC frequency over `window_nanos` of the `reference` clock,
+/// which returns nanoseconds.
+///
+/// Starts and stops right after the reference clock changed, so a clock
+/// that only advances in steps, like the PIT tick count, gives exact windows.
+pub(super) fn calibrate(reference: impl Fn() -> u64, window_nanos: u64) -> u64 {
+    let first = reference();
+    let mut start = first;
+    while start == first {
+        start = reference();
+    }
+    let tsc_start = read();
+    let mut now = start;
+    while now.wrapping_sub(start) < window_nanos {
+        core::hint::spin_loop();
+        now = reference();
+    }
+    let tsc_end = read();
+    let elapsed = now.wrapping_sub(start);
+    let frequency = (tsc_end.wrapping_sub(tsc_start) as u128 * 1_000_000_000 / elapsed as u128) as u64;
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
@@ -0,0 +1,80 @@
+use core::{fmt, time::Duration};
+
+use spin::Once;
+
+use super::monotonic_now;
+use crate::rtc::{self, DateTime};
+
+/// A point in calendar time, like `std::time::SystemTime`, stored as the
+/// time since `UNIX_EPOCH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(Duration);
+
+/// 1970-01-01 00:00:00 UTC.
+pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::ZERO);
+
+// the calendar time at which `monotonic_now` was zero
+static BOOT_TIME: Once<Duration> = Once::new();
+
+// falls back to `UNIX_EPOCH` if the RTC holds garbage
+fn boot_time() -> Duration {
+    *BOOT_TIME.call_once(|| {
+        let rtc_time = match rtc::read() {
+            Ok(date_time) => Duration::from_secs(date_time.to_unix_secs()),
+            Err(err) => {
+                crate::log!("{}, counting from 1970 instead", err);
+                Duration::ZERO
+            }
+        };
+        rtc_time.saturating_sub(monotonic_now())
+    })
+}
+
+impl SystemTime {
+    /// The current time: the RTC is read on the first call and
+    /// `monotonic_now` advances it from there, so it is as accurate as the
+    /// second the RTC counts in but never jumps.
+    pub fn now() -> SystemTime {
+        SystemTime(boot_time() + monotonic_now())
+    }
+
+    /// The time of boot, as far as the RTC tells, or `UNIX_EPOCH` if the RTC
+    /// does not hold a valid date.
+    pub fn boot() -> SystemTime {
+        SystemTime(boot_time())
+    }
+
+    /// The calendar time of `date_time`, which must be valid, see `DateTime::is_valid`.
+    pub fn from_date_time(date_time: DateTime) -> SystemTime {
+        SystemTime(Duration::from_secs(date_time.to_unix_secs()))
+    }
+
+    /// The date and time, truncated to seconds.
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_secs(self.0.as_secs())
+    }
+
+    /// The time from `earlier` to `self`, or `None` if `earlier` is later.
+    pub fn duration_since(&self, earlier: SystemTime) -> Option<Duration> {
+        self.0.checked_sub(earlier.0)
+    }
+
+    /// The time that passed since `self`, or `None` if it is in the future.
+    pub fn elapsed(&self) -> Option<Duration> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_add(duration).map(SystemTime)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<SystemTime> {
+        self.0.checked_sub(duration).map(SystemTime)
+    }
+}
+
+impl fmt::Display for SystemTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} UTC", self.date_time())
+    }
+}
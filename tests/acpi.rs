@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{acpi::{self, fadt::RegisterSpace, AcpiTables}, allocator, hlt_loop, memory};
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn root_table_lists_the_tables() {
+    let tables = AcpiTables::find().unwrap();
+    assert!(tables.table_addresses().count() >= 2);
+    assert!(tables.find_table(*b"FACP").is_ok());
+    assert_eq!(tables.find_table(*b"NONE"), Err(acpi::AcpiError::TableNotFound(*b"NONE")));
+}
+
+#[test_case]
+fn init_is_cached() {
+    let info = acpi::init().unwrap();
+    assert!(core::ptr::eq(info, acpi::init().unwrap()));
+    assert!(core::ptr::eq(info, acpi::info().unwrap()));
+}
+
+#[test_case]
+fn fadt_describes_the_pm_timer() {
+    let fadt = &acpi::init().unwrap().fadt;
+    assert_ne!(fadt.sci_interrupt, 0);
+    assert!(fadt.has_legacy_devices());
+    let timer = fadt.pm_timer_block.unwrap();
+    assert_eq!(timer.space, RegisterSpace::SystemIo);
+
+    // the timer runs at 3.58 MHz, so it moves between a few port reads
+    let mut port = Port::<u32>::new(timer.io_port().unwrap());
+    let first = unsafe { port.read() };
+    assert!((0..1000).any(|_| unsafe { port.read() } != first));
+}
+
+#[test_case]
+fn hpet_is_at_the_standard_address() {
+    let hpet = acpi::init().unwrap().hpet.unwrap();
+    assert_eq!(hpet.base_address, PhysAddr::new(0xfed0_0000));
+    assert!(hpet.comparators >= 3);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
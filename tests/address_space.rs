@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, hlt_loop, memory::{self, address_space::{self, USER_START}, AddressSpace}};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames())
+}
+
+fn map_user_page(space: &mut AddressSpace, addr: VirtAddr) {
+    space.with_mapper(|mapper, frame_allocator| {
+        let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(Page::containing_address(addr), frame, flags, frame_allocator) }
+            .unwrap()
+            .flush();
+    });
+}
+
+#[test_case]
+fn kernel_half_is_shared() {
+    let value = Box::new(41);
+    let space = AddressSpace::new().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        space.activate();
+        assert!(space.is_active());
+        assert_eq!(*value + 1, 42);
+        if address_space::pcid_enabled() {
+            assert_ne!(Cr3::read_pcid().1.value(), 0);
+        }
+        address_space::activate_kernel();
+    });
+    assert!(!space.is_active());
+}
+
+#[test_case]
+fn new_kernel_mappings_reach_the_active_space() {
+    // far from the bootloader's mappings and every `vmm::Region`
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x7f00_0000_0000));
+    let space = AddressSpace::new().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        space.activate();
+        let frame = memory::with_kernel_memory(|m| {
+            let frame: PhysFrame<Size4KiB> = m.frame_allocator.allocate_frame().unwrap();
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            m.mapper.map_to(page, frame, flags, &mut m.frame_allocator).unwrap().flush();
+            frame
+        });
+        let ptr = page.start_address().as_mut_ptr::<u64>();
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+        address_space::activate_kernel();
+        memory::with_kernel_memory(|m| {
+            m.mapper.unmap(page).unwrap().1.flush();
+            m.frame_allocator.deallocate_frame(frame);
+        });
+    });
+}
+
+#[test_case]
+fn user_half_is_private() {
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    map_user_page(&mut a, USER_START);
+    map_user_page(&mut b, USER_START);
+
+    let ptr = USER_START.as_mut_ptr::<u64>();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        a.activate();
+        ptr.write_volatile(1);
+        b.activate();
+        ptr.write_volatile(2);
+        a.activate();
+        assert_eq!(ptr.read_volatile(), 1);
+        address_space::activate_kernel();
+    });
+
+    assert!(memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, USER_START)).is_none());
+}
+
+#[test_case]
+fn remapping_an_inactive_space_is_seen() {
+    let mut space = AddressSpace::new().unwrap();
+    map_user_page(&mut space, USER_START);
+    let ptr = USER_START.as_mut_ptr::<u64>();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        space.activate();
+        ptr.write_volatile(1);
+        address_space::activate_kernel();
+    });
+
+    // the TLB entry of the old frame must not survive the next switch
+    space.with_mapper(|mapper, frame_allocator| {
+        let page = Page::<Size4KiB>::containing_address(USER_START);
+        let frame: PhysFrame<Size4KiB> = frame_allocator.allocate_frame().unwrap();
+        let contents = mapper.phys_offset() + frame.start_address().as_u64();
+        unsafe { contents.as_mut_ptr::<u64>().write_volatile(2) };
+        let (old, flush) = mapper.unmap(page).unwrap();
+        flush.ignore();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator) }.unwrap().ignore();
+        unsafe { frame_allocator.deallocate_frame(old) };
+    });
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        space.activate();
+        assert_eq!(ptr.read_volatile(), 2);
+        address_space::activate_kernel();
+    });
+}
+
+#[test_case]
+fn teardown_frees_all_frames() {
+    let free = free_frames();
+    let mut space = AddressSpace::new().unwrap();
+    // spread the pages out, so that every level of the hierarchy has several tables
+    for offset in [0u64, 4096, 2 << 20, 1 << 30, 1 << 39] {
+        map_user_page(&mut space, USER_START + offset);
+    }
+    assert!(free_frames() < free);
+
+    drop(space);
+    assert_eq!(free_frames(), free);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{acpi::AcpiTables, allocator, apic, hlt_loop, interrupts::{self, Controller, InterruptIndex}, memory, time};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn madt_lists_the_controllers() {
+    let madt = AcpiTables::find().unwrap().madt().unwrap();
+    assert!(madt.processors.iter().any(|p| p.enabled));
+    assert!(!madt.io_apics.is_empty());
+    assert!(!madt.local_apic_address.is_null());
+    // QEMU connects the PIT to GSI 2
+    assert_eq!(madt.isa_irq(InterruptIndex::Timer.irq()).0, 2);
+}
+
+#[test_case]
+fn timer_interrupts_arrive_through_the_io_apic() {
+    assert_eq!(interrupts::controller(), Controller::Pic8259);
+    apic::init().unwrap();
+    assert_eq!(interrupts::controller(), Controller::Apic);
+
+    let madt = AcpiTables::find().unwrap().madt().unwrap();
+    let id = apic::local_apic().unwrap().id();
+    assert!(madt.processors.iter().any(|p| p.apic_id == id));
+
+    // the PICs are masked, so every tick from here on came through the I/O APIC
+    let ticks = time::ticks();
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+    assert!(time::ticks() > ticks, "no timer interrupt arrived");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
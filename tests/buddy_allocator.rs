@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, sync::atomic::{AtomicU64, Ordering}};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory::{self, buddy::MAX_ORDER, report::{MemoryMapSummary, Size}, Zone}, allocator};
+use x86_64::{structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB}, VirtAddr};
+
+entry_point!(main);
+
+// usable bytes according to the memory map
+static USABLE: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+    USABLE.store(MemoryMapSummary::new(&boot_info.memory_map).usable, Ordering::Relaxed);
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+
+    // the heap is mapped with frames from the buddy allocator
+    allocator::init_heap().expect("Heap initialization error");
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn heap_backed_by_buddy_frames() {
+    let x = Box::new(42);
+    assert_eq!(*x, 42);
+}
+
+#[test_case]
+fn blocks_are_aligned_to_their_size() {
+    memory::with_kernel_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+
+        for order in 0..6 {
+            let frame = allocator.allocate_frames(order).unwrap();
+            assert_eq!(frame.start_address().as_u64() % (4096 << order), 0);
+            unsafe { allocator.deallocate_frames(frame, order) };
+        }
+    });
+}
+
+#[test_case]
+fn freed_blocks_are_merged() {
+    memory::with_kernel_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+
+        let free_before = allocator.free_frames();
+        let mut blocks_before = [0; MAX_ORDER + 1];
+        for (order, count) in blocks_before.iter_mut().enumerate() {
+            *count = allocator.free_blocks(order);
+        }
+
+        let block = allocator.allocate_frames(4).unwrap();
+        assert_eq!(allocator.free_frames(), free_before - 16);
+        assert!(!allocator.is_free(block));
+
+        // split the block by hand and return the frames one at a time
+        unsafe {
+            for i in 0..16u64 {
+                let frame = PhysFrame::containing_address(block.start_address() + i * 4096);
+                allocator.deallocate_frames(frame, 0);
+            }
+        }
+        assert_eq!(allocator.free_frames(), free_before);
+        // the frames merged into a larger block, but are still known to be free
+        for i in 0..16u64 {
+            assert!(allocator.is_free(PhysFrame::containing_address(block.start_address() + i * 4096)));
+        }
+
+        // after merging, the free lists look exactly like before the allocation
+        for (order, count) in blocks_before.iter().enumerate() {
+            assert_eq!(allocator.free_blocks(order), *count);
+        }
+    });
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    memory::with_kernel_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+
+        let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64() % (2 * 1024 * 1024), 0);
+        unsafe { allocator.deallocate_frame(frame) };
+    });
+}
+
+#[test_case]
+fn meminfo_matches_allocator() {
+    let info = memory::meminfo();
+    assert_eq!(info.free_frames, memory::with_kernel_memory(|m| m.frame_allocator.free_frames()));
+    assert_eq!(info.used_frames() + info.free_frames, info.total_frames);
+    // the heap is mapped, and the allocator metadata is not handed out
+    assert!(info.used_frames() > 0);
+    assert!((info.total_frames as u64) * 4096 < USABLE.load(Ordering::Relaxed));
+}
+
+#[test_case]
+fn sizes_use_exact_units() {
+    use alloc::string::ToString;
+    assert_eq!(Size(512).to_string(), "512 B");
+    assert_eq!(Size(4096).to_string(), "4 KiB");
+    assert_eq!(Size(127 * 1024 * 1024).to_string(), "127 MiB");
+    assert_eq!(Size(1024 * 1024 + 4096).to_string(), "1028 KiB");
+}
+
+#[test_case]
+fn zone_allocations_respect_their_limit() {
+    memory::with_kernel_memory(|memory| {
+        let allocator = &mut memory.frame_allocator;
+        for zone in [Zone::Dma, Zone::Dma32] {
+            let free = allocator.zone_stats(zone).free_frames;
+            let frame = allocator.allocate_frames_in(zone, 2).unwrap();
+            assert!(frame.start_address() + 4u64 * 4096 <= zone.end());
+            assert!(Zone::containing(frame.start_address()) <= zone);
+            unsafe { allocator.deallocate_frames(frame, 2) };
+            assert_eq!(allocator.zone_stats(zone).free_frames, free);
+        }
+    });
+}
+
+#[test_case]
+fn zone_stats_add_up() {
+    let info = memory::meminfo();
+    assert_eq!(info.zones.iter().map(|z| z.total_frames).sum::<usize>(), info.total_frames);
+    assert_eq!(info.zones.iter().map(|z| z.free_frames).sum::<usize>(), info.free_frames);
+    // QEMU's default memory size fits below 4 GiB
+    assert!(info.zones[Zone::Dma32 as usize].total_frames > 0);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
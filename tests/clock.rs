@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, time::Duration};
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, hlt_loop, hpet, memory, time::{self, tsc, ClockSource}};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    time::init_clocks();
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn hpet_counter_runs() {
+    // QEMU emulates an HPET on the pc machine
+    let hpet = hpet::hpet().expect("no HPET");
+    assert!(hpet.frequency() >= 10_000_000);
+    let start = hpet.counter();
+    time::sleep_ms(10);
+    let elapsed = hpet.ticks_to_nanos(hpet.counter() - start);
+    assert!((9_000_000..50_000_000).contains(&elapsed), "{} ns", elapsed);
+}
+
+#[test_case]
+fn tsc_is_calibrated() {
+    let frequency = tsc::frequency().expect("TSC not calibrated");
+    assert!(frequency >= 100_000_000, "{} Hz", frequency);
+}
+
+#[test_case]
+fn uses_a_high_resolution_source() {
+    let expected = if tsc::is_invariant() { ClockSource::Tsc } else { ClockSource::Hpet };
+    assert_eq!(time::clock_source(), expected);
+}
+
+#[test_case]
+fn monotonic_now_never_goes_back() {
+    let mut last = time::monotonic_now();
+    for _ in 0..10_000 {
+        let now = time::monotonic_now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn monotonic_now_is_finer_than_a_tick() {
+    let start = time::monotonic_now();
+    let mut now = start;
+    while now == start {
+        now = time::monotonic_now();
+    }
+    assert!(now - start < Duration::from_micros(100), "{:?}", now - start);
+}
+
+#[test_case]
+fn monotonic_now_follows_sleep() {
+    let (_, elapsed) = time::measure(|| time::sleep_ms(50));
+    // QEMU may deliver timer interrupts late
+    assert!((Duration::from_millis(48)..Duration::from_millis(100)).contains(&elapsed), "{:?}", elapsed);
+}
+
+#[test_case]
+fn timestamps_show_microseconds() {
+    let stamp = time::Timestamp(Duration::new(12, 345_678_901));
+    assert_eq!(format!("{}", stamp), "   12.345678");
+    h_os::log!("logged at {}", stamp);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
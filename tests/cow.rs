@@ -0,0 +1,132 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory::{self, cow, vmm::{self, Region}}};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        FrameAllocator, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Translate,
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+
+    test_main();
+
+    hlt_loop()
+}
+
+/// A second level 4 table that shares everything with the active one,
+/// except for the part of the address space that contains `private`.
+fn new_page_table(private: VirtAddr) -> (PhysFrame, OffsetPageTable<'static>) {
+    memory::with_kernel_memory(|m| {
+        let offset = m.mapper.phys_offset();
+        let frame: PhysFrame = m.frame_allocator.allocate_frame().unwrap();
+        let table = unsafe { &mut *(offset + frame.start_address().as_u64()).as_mut_ptr::<PageTable>() };
+        table.zero();
+        for (index, entry) in m.mapper.level_4_table().iter().enumerate() {
+            if index != usize::from(private.p4_index()) {
+                table[index] = entry.clone();
+            }
+        }
+        (frame, unsafe { OffsetPageTable::new(table, offset) })
+    })
+}
+
+fn frame_of(mapper: &OffsetPageTable, addr: VirtAddr) -> PhysFrame {
+    PhysFrame::containing_address(mapper.translate_addr(addr).unwrap())
+}
+
+fn active_frame_of(addr: VirtAddr) -> PhysFrame {
+    memory::with_kernel_memory(|m| frame_of(&m.mapper, addr))
+}
+
+fn read_phys(mapper: &OffsetPageTable, addr: VirtAddr) -> u64 {
+    let phys = mapper.translate_addr(addr).unwrap();
+    unsafe { (mapper.phys_offset() + phys.as_u64()).as_ptr::<u64>().read_volatile() }
+}
+
+#[test_case]
+fn write_gets_private_copy() {
+    let range = vmm::reserve(Region::PerCpu, 4096).unwrap();
+    let page = Page::containing_address(range.start);
+    let ptr = range.start.as_mut_ptr::<u64>();
+    vmm::map_range(&range, PageTableFlags::WRITABLE).unwrap();
+    unsafe { ptr.write_volatile(1) };
+
+    let (dst_frame, mut dst) = new_page_table(range.start);
+    memory::with_kernel_memory(|m| {
+        cow::share(&mut m.mapper, page, &mut dst, page, &mut m.frame_allocator).unwrap();
+    });
+    let original = active_frame_of(range.start);
+    assert_eq!(frame_of(&dst, range.start), original);
+    assert_eq!(cow::ref_count(original), 2);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, range.start)).unwrap();
+    assert!(!translation.is_writable() && translation.flags.contains(cow::COW));
+
+    // writing through the active table copies the frame
+    unsafe {
+        ptr.write_volatile(2);
+        assert_eq!(ptr.read_volatile(), 2);
+    }
+    assert_ne!(active_frame_of(range.start), original);
+    assert_eq!(read_phys(&dst, range.start), 1);
+    assert_eq!(cow::ref_count(original), 1);
+
+    // the other table is the last user now and writes without copying
+    let (old_frame, flags) = Cr3::read();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Cr3::write(dst_frame, flags);
+        ptr.write_volatile(3);
+        Cr3::write(old_frame, flags);
+    });
+    assert_eq!(frame_of(&dst, range.start), original);
+    assert_eq!(read_phys(&dst, range.start), 3);
+    assert_eq!(unsafe { ptr.read_volatile() }, 2);
+
+    memory::with_kernel_memory(|m| cow::unmap(&mut dst, page, &mut m.frame_allocator)).unwrap();
+    vmm::unmap_range(&range);
+    vmm::release(range);
+}
+
+#[test_case]
+fn shared_frame_is_freed_by_last_unmap() {
+    let range = vmm::reserve(Region::PerCpu, 4096).unwrap();
+    let page = Page::containing_address(range.start);
+    vmm::map_range(&range, PageTableFlags::WRITABLE).unwrap();
+
+    let (_, mut dst) = new_page_table(range.start);
+    memory::with_kernel_memory(|m| {
+        cow::share(&mut m.mapper, page, &mut dst, page, &mut m.frame_allocator).unwrap();
+    });
+    let frame = active_frame_of(range.start);
+    let free = memory::with_kernel_memory(|m| m.frame_allocator.free_frames());
+
+    vmm::unmap_range(&range);
+    assert_eq!(cow::ref_count(frame), 1);
+    assert_eq!(memory::with_kernel_memory(|m| m.frame_allocator.free_frames()), free);
+
+    memory::with_kernel_memory(|m| cow::unmap(&mut dst, page, &mut m.frame_allocator)).unwrap();
+    assert_eq!(memory::with_kernel_memory(|m| m.frame_allocator.free_frames()), free + 1);
+    vmm::release(range);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
@@ -0,0 +1,96 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory::{self, vmm::{self, Region}, KernelStack}};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+
+    test_main();
+
+    hlt_loop()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).is_some()
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames())
+}
+
+#[test_case]
+fn pages_are_backed_on_first_touch() {
+    let range = vmm::reserve(Region::Mmio, 4 * 4096).unwrap();
+    // map once so that the page tables for the range exist
+    vmm::map_range(&range, PageTableFlags::WRITABLE).unwrap();
+    vmm::unmap_range(&range);
+    let free = free_frames();
+
+    assert!(vmm::map_lazy(&range, PageTableFlags::WRITABLE));
+    assert_eq!(free_frames(), free);
+    assert!(!is_mapped(range.start));
+
+    let ptr = (range.start + 4096u64).as_mut_ptr::<u64>();
+    unsafe {
+        // fresh pages are zeroed
+        assert_eq!(ptr.add(1).read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert!(is_mapped(range.start + 4096u64));
+    assert!(!is_mapped(range.start));
+    assert_eq!(free_frames(), free - 1);
+
+    vmm::unmap_range(&range);
+    assert!(!is_mapped(range.start + 4096u64));
+    assert_eq!(free_frames(), free);
+    vmm::release(range);
+}
+
+#[test_case]
+fn read_only_pages_stay_read_only() {
+    let range = vmm::reserve(Region::Mmio, 4096).unwrap();
+    assert!(vmm::map_lazy(&range, PageTableFlags::empty()));
+
+    assert_eq!(unsafe { range.start.as_ptr::<u64>().read_volatile() }, 0);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, range.start)).unwrap();
+    assert!(!translation.is_writable() && !translation.is_executable());
+
+    vmm::unmap_range(&range);
+    vmm::release(range);
+}
+
+#[test_case]
+fn lazy_stack_grows_on_demand() {
+    let stack = KernelStack::new_lazy(16).unwrap();
+    assert!(is_mapped(stack.top() - 1u64));
+    assert!(!is_mapped(stack.bottom()));
+
+    let ptr = stack.bottom().as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(is_mapped(stack.bottom()));
+    assert!(!is_mapped(stack.guard_page().start_address()));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
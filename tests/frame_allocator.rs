@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory::BitmapFrameAllocator};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{structures::paging::{FrameAllocator, FrameDeallocator}, VirtAddr};
+
+lazy_static! {
+    static ref FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn allocated_frames_are_distinct() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let a = allocator.allocate_frame().unwrap();
+    let b = allocator.allocate_frame().unwrap();
+    assert_ne!(a, b);
+
+    unsafe {
+        allocator.deallocate_frame(a);
+        allocator.deallocate_frame(b);
+    }
+}
+
+#[test_case]
+fn deallocated_frame_is_reused() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let free_before = allocator.free_frames();
+    let frame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.free_frames(), free_before - 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_before);
+    assert_eq!(allocator.allocate_frame(), Some(frame));
+
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn many_allocations_with_reuse() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    // far more allocations than a small QEMU machine has frames
+    for _ in 0..100_000 {
+        let frame = allocator.allocate_frame().expect("frames are not reused");
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::{arch::asm, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{serial_println, exit_qemu, gdt, hlt_loop, memory::{self, vmm::Region, KernelStack}};
+use lazy_static::lazy_static;
+use x86_64::{structures::idt::{InterruptDescriptorTable, InterruptStackFrame}, VirtAddr};
+
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_println!("Ready to begin GUARDED STACK test");
+    gdt::init();
+    init_test_idt();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    gdt::init_stacks().expect("IST stack allocation failed");
+    assert!(Region::Stacks.contains(gdt::ist_stack_top(gdt::DOUBLE_FAULT_IST_INDEX) - 1u64));
+
+    // run the overflow on a runtime allocated stack, like a future kernel thread
+    let stack = KernelStack::new(4).expect("stack allocation failed");
+    let top = stack.leak();
+    unsafe {
+        asm!("mov rsp, {0}", "call {1}", in(reg) top.as_u64(), in(reg) overflow as fn() as usize, options(noreturn));
+    }
+}
+
+// will triger triple fault if the guard page does not work
+#[allow(unconditional_recursion)]
+fn overflow() {
+    overflow();
+    // avoid tail recursion optimization
+    volatile::Volatile::new(2).read();
+}
+
+#[panic_handler]
+fn panic(info : &PanicInfo) -> !{
+    h_os::test_panic_handler(info);
+}
+
+lazy_static!{
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe{
+            idt.double_fault.set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+extern "x86-interrupt" fn double_fault_handler(_stack_frame: InterruptStackFrame, _errnum: u64) -> !{
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp) };
+
+    // the handler must run on the guarded IST stack, not the static boot stack
+    if Region::Stacks.contains(VirtAddr::new(rsp)) {
+        serial_println!("GUARDED STACK test ... \x1b[42m[OK]\x1b[0m");
+        exit_qemu(h_os::QemuExitCode::Success);
+    } else {
+        serial_println!("double fault handler runs on {:#x}", rsp);
+        exit_qemu(h_os::QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+fn init_test_idt() {
+    IDT.load();
+}
@@ -6,9 +6,9 @@
 
 use core::panic::PanicInfo;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
-use h_os::{hlt_loop, memory::{self, BootInfoFrameAllocator}, allocator::{self, HEAP_SIZE}};
+use h_os::{hlt_loop, memory, allocator::{self, AllocError, HEAP_SIZE, MAX_TRACKED_ALLOCATIONS}};
 use x86_64::VirtAddr;
 
 extern crate alloc;
@@ -22,12 +22,11 @@ fn main(boot_info: &'static BootInfo) -> !{
     
     // initialize heap
     let offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe{memory::init(offset)};
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization error");
+    allocator::init_heap().expect("Heap initialization error");
     
     test_main();
 
@@ -56,10 +55,110 @@ fn large_vec() {
 
 #[test_case]
 fn many_boxes(){
+    let before = allocator::stats();
+    let (_, leaks) = allocator::track_allocations(|| {
+        for i in 0..HEAP_SIZE {
+            let x = Box::new(i);
+            assert_eq!(*x,i);
+        }
+    });
+    let after = allocator::stats();
+
+    // every box must really be freed, not just fit into the heap
+    assert!(leaks.is_empty(), "{:?}", leaks);
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.allocations - before.allocations, HEAP_SIZE);
+    assert_eq!(after.frees - before.frees, HEAP_SIZE);
+}
+
+#[test_case]
+fn leaks_are_reported() {
+    let (leaked, leaks) = allocator::track_allocations(|| {
+        let _freed = Box::new(1u64);
+        Box::leak(Box::new(2u64)) as *mut u64
+    });
+
+    assert_eq!(leaks.count(), 1);
+    let leak = leaks.iter().next().unwrap();
+    assert_eq!(leak.address, leaked as usize);
+    assert_eq!(leak.size, 8);
+    assert_ne!(leak.caller[0], 0);
+}
+
+#[test_case]
+fn untracked_leaks_are_counted() {
+    // allocated before tracking starts, and of another size class than the boxes
+    let old = vec![0u8; 4096];
+    let (boxes, leaks) = allocator::track_allocations(|| {
+        let boxes: Vec<Box<u64>> = (0..MAX_TRACKED_ALLOCATIONS as u64 + 8).map(Box::new).collect();
+        drop(old);
+        boxes
+    });
+
+    // the vector and the boxes, freeing `old` must not hide any of the untracked ones
+    assert_eq!(leaks.count(), MAX_TRACKED_ALLOCATIONS + 9);
+    assert_eq!(leaks.untracked(), 9);
+    drop(boxes);
+}
+
+#[test_case]
+fn stats_track_usage() {
+    let before = allocator::stats();
+    let v: Vec<u8> = Vec::with_capacity(4096);
+    let during = allocator::stats();
+    assert_eq!(during.bytes_in_use, before.bytes_in_use + 4096);
+    assert!(during.peak_bytes_in_use >= during.bytes_in_use);
+    assert!(during.largest_free_block <= during.heap_size);
+
+    drop(v);
+    assert_eq!(allocator::stats().bytes_in_use, before.bytes_in_use);
+}
+
+// keeps far more than the initial heap alive at the same time,
+// which only works if the heap grows on demand
+#[test_case]
+fn heap_grows_beyond_initial_size() {
+    let large = vec![1u8; 4 * HEAP_SIZE];
+    let mut boxes = Vec::new();
+    for i in 0..4096 {
+        boxes.push(Box::new([i as u8; 1024]));
+    }
+
+    assert!(allocator::heap_size() >= 8 * HEAP_SIZE);
+    assert!(large.iter().all(|&b| b == 1));
+    for (i, b) in boxes.iter().enumerate() {
+        assert_eq!(b[1023], i as u8);
+    }
+}
+
+#[test_case]
+fn fallible_allocation_survives_exhaustion() {
+    let too_large = allocator::heap_limit() * 2;
+    assert!(matches!(
+        allocator::try_vec_with_capacity::<u8>(too_large),
+        Err(AllocError::OutOfMemory(_))
+    ));
+    assert_eq!(
+        allocator::try_vec_with_capacity::<u64>(usize::MAX).unwrap_err(),
+        AllocError::CapacityOverflow
+    );
+
+    // the kernel keeps going and small allocations still work
+    let v = allocator::try_vec_with_capacity::<u8>(128).unwrap();
+    assert!(v.capacity() >= 128);
+    assert_eq!(*allocator::try_box(42).unwrap(), 42);
+}
+
+// a long lived allocation must not keep freed blocks from being reused,
+// both with the linked list heap and the `fixed_size_block` allocator
+#[test_case]
+fn many_boxes_long_lived(){
+    let long_lived = Box::new(1);
     for i in 0..HEAP_SIZE {
         let x = Box::new(i);
         assert_eq!(*x,i);
     }
+    assert_eq!(*long_lived, 1);
 }
 
 #[panic_handler]
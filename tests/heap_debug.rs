@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator::{self, debug::{self, CorruptionKind}}, hlt_loop, memory};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn intact_allocations_pass() {
+    let boxed = Box::new([7u64; 3]);
+    assert_eq!(unsafe { debug::check_allocation(boxed.as_ptr() as *const u8) }, Ok(()));
+}
+
+#[test_case]
+fn overflow_is_detected() {
+    let mut buffer: Vec<u8> = Vec::with_capacity(10);
+    let ptr = buffer.as_mut_ptr();
+    unsafe {
+        ptr.add(10).write(0);
+        let corruption = debug::check_allocation(ptr).unwrap_err();
+        assert_eq!(corruption.kind, CorruptionKind::BackRedZone);
+        assert_eq!(corruption.size, 10);
+        assert_ne!(corruption.caller[0], 0);
+        // repair it, so that the free below succeeds
+        ptr.add(10).write(debug::CANARY);
+    }
+}
+
+#[test_case]
+fn underflow_is_detected() {
+    let mut buffer: Vec<u8> = Vec::with_capacity(10);
+    let ptr = buffer.as_mut_ptr();
+    unsafe {
+        ptr.sub(1).write(0);
+        assert_eq!(debug::check_allocation(ptr).unwrap_err().kind, CorruptionKind::FrontRedZone);
+        ptr.sub(1).write(debug::CANARY);
+    }
+}
+
+#[test_case]
+fn freed_memory_is_poisoned() {
+    let ptr = Box::into_raw(Box::new([0x11u8; 32])) as *mut u8;
+    unsafe {
+        drop(Box::from_raw(ptr as *mut [u8; 32]));
+        // the block is not reused before these checks
+        let poisoned = (0..32).all(|i| ptr.add(i).read_volatile() == debug::POISON);
+        let corruption = debug::check_allocation(ptr).unwrap_err();
+        assert!(poisoned);
+        assert_eq!(corruption.kind, CorruptionKind::DoubleFree);
+        assert_eq!(corruption.size, 32);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
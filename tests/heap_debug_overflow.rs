@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, sync::atomic::{AtomicBool, Ordering}};
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, exit_qemu, hlt_loop, memory, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+// set right before the corrupted buffer is freed
+static FREEING: AtomicBool = AtomicBool::new(false);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    serial_print!("heap_debug_overflow::smashed_canary_panics_on_free...\t");
+    let mut buffer: Vec<u8> = Vec::with_capacity(16);
+    unsafe { buffer.as_mut_ptr().add(16).write(0) };
+    FREEING.store(true, Ordering::SeqCst);
+    drop(buffer);
+
+    serial_println!("smashed canary was not reported");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    if FREEING.load(Ordering::SeqCst) {
+        // the report must not hold the heap lock, or this would hang
+        drop(alloc::boxed::Box::new(0u8));
+        serial_println!("\x1b[42m[OK]\x1b[0m");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop();
+    }
+    h_os::test_panic_handler(info);
+}
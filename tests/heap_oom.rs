@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use h_os::{QemuExitCode, exit_qemu, serial_println, hlt_loop, memory, allocator::{self, HEAP_SIZE}};
+use x86_64::VirtAddr;
+
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization error");
+
+    // keep the heap from growing, so the allocation below can not succeed
+    allocator::set_heap_limit(HEAP_SIZE);
+    let v: Vec<u8> = Vec::with_capacity(2 * HEAP_SIZE);
+
+    serial_println!("allocation of {} bytes did not fail", v.capacity());
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+// the OOM handler reports the failure and then panics, any other panic
+// means the test failed before reaching it
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    if matches!(info.location(), Some(location) if location.file().ends_with("allocator/oom.rs")) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed] unexpected panic: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
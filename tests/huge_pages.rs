@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, hlt_loop, memory::{self, vmm::{self, Region}}, serial_print};
+use x86_64::{
+    structures::paging::{mapper::{MapToError, MappedFrame}, PageSize, PageTableFlags, Size1GiB, Size2MiB},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+fn frame_of(addr: VirtAddr) -> Option<MappedFrame> {
+    memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).map(|t| t.frame)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames())
+}
+
+#[test_case]
+fn map_and_unmap_2mib_pages() {
+    let range = vmm::reserve_aligned(Region::Mmio, 2 * Size2MiB::SIZE, Size2MiB::SIZE).unwrap();
+    // map once so that the page tables for the range exist
+    vmm::map_range_sized::<Size2MiB>(&range, PageTableFlags::WRITABLE).unwrap();
+    vmm::unmap_range(&range);
+    let free = free_frames();
+
+    vmm::map_range_sized::<Size2MiB>(&range, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(free_frames(), free - 2 * 512);
+    assert!(matches!(frame_of(range.start), Some(MappedFrame::Size2MiB(_))));
+
+    let last = (range.end() - 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        last.write_volatile(0xdead_beef);
+        assert_eq!(last.read_volatile(), 0xdead_beef);
+    }
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, range.start)).unwrap();
+    assert!(translation.is_writable() && !translation.is_executable());
+
+    vmm::unmap_range(&range);
+    assert_eq!(free_frames(), free);
+    assert!(frame_of(range.start).is_none());
+    vmm::release(range);
+}
+
+#[test_case]
+fn map_and_unmap_1gib_pages() {
+    assert!(vmm::page_size_supported::<Size2MiB>());
+    // QEMU's default CPU model has no 1GiB pages, and its default memory
+    // size no free 1GiB block
+    if !vmm::page_size_supported::<Size1GiB>() {
+        serial_print!("[skipped: no 1GiB page support] ");
+        return;
+    }
+    let range = vmm::reserve_aligned(Region::Mmio, Size1GiB::SIZE, Size1GiB::SIZE).unwrap();
+    let free = free_frames();
+    match vmm::map_range_sized::<Size1GiB>(&range, PageTableFlags::WRITABLE) {
+        Ok(()) => {}
+        Err(MapToError::FrameAllocationFailed) => {
+            vmm::release(range);
+            serial_print!("[skipped: no free 1GiB block] ");
+            return;
+        }
+        Err(err) => panic!("mapping a 1GiB page failed: {:?}", err),
+    }
+    let mapped = free_frames();
+    assert!(free - mapped >= 512 * 512);
+
+    let addr = range.start + 0x1234_5678u64;
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).unwrap();
+    let frame = match translation.frame {
+        MappedFrame::Size1GiB(frame) => frame,
+        frame => panic!("mapped with {:?}", frame),
+    };
+    assert_eq!(translation.phys_addr, frame.start_address() + 0x1234_5678u64);
+    assert!(translation.is_writable() && !translation.is_executable());
+    let last = (range.end() - 8u64).as_mut_ptr::<u64>();
+    unsafe {
+        last.write_volatile(0xdead_beef);
+        assert_eq!(last.read_volatile(), 0xdead_beef);
+    }
+
+    vmm::unmap_range(&range);
+    assert!(frame_of(range.start).is_none());
+    assert_eq!(free_frames(), mapped + 512 * 512);
+    vmm::release(range);
+}
+
+#[test_case]
+fn large_heap_growth_uses_2mib_pages() {
+    let buffer: Vec<u8> = Vec::with_capacity(4 * Size2MiB::SIZE as usize);
+    let start = VirtAddr::from_ptr(buffer.as_ptr());
+    let aligned = start.align_up(Size2MiB::SIZE);
+    assert!(matches!(frame_of(aligned), Some(MappedFrame::Size2MiB(_))));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
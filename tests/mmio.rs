@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory::{self, ioremap, vmm::Region}};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+const VGA_BUFFER: u64 = 0xb8000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+
+    test_main();
+
+    hlt_loop()
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames())
+}
+
+#[test_case]
+fn maps_device_memory_uncached() {
+    let mem = unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 80 * 25 * 2) }.unwrap();
+    assert!(Region::Mmio.contains(mem.base()));
+
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, mem.base())).unwrap();
+    assert_eq!(translation.phys_addr, PhysAddr::new(VGA_BUFFER));
+    assert!(translation.flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+    assert!(!translation.is_executable());
+}
+
+#[test_case]
+fn accesses_reach_the_device() {
+    let mem = unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 80 * 25 * 2) }.unwrap();
+    let last = 80 * 25 * 2 - 2;
+    // a light gray 'X' in the bottom right corner
+    mem.write::<u16>(last, 0x0758);
+    assert_eq!(mem.read::<u16>(last), 0x0758);
+
+    let offset = memory::with_kernel_memory(|m| m.mapper.phys_offset());
+    let direct = (offset + VGA_BUFFER + last as u64).as_ptr::<u16>();
+    assert_eq!(unsafe { direct.read_volatile() }, 0x0758);
+}
+
+#[test_case]
+fn unaligned_ranges_keep_their_offset() {
+    let mem = unsafe { ioremap(PhysAddr::new(VGA_BUFFER + 0xffe), 4) }.unwrap();
+    assert_eq!(mem.base().as_u64() % 4096, 0xffe);
+    let second = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, mem.base() + 2u64)).unwrap();
+    assert_eq!(second.phys_addr, PhysAddr::new(VGA_BUFFER + 0x1000));
+}
+
+#[test_case]
+fn drop_unmaps_without_freeing_frames() {
+    let mem = unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 4096) }.unwrap();
+    let base = mem.base();
+    drop(mem);
+    assert!(memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, base)).is_none());
+
+    // the page tables exist by now, so a new mapping neither takes nor gives back frames
+    let free = free_frames();
+    drop(unsafe { ioremap(PhysAddr::new(VGA_BUFFER), 4096) }.unwrap());
+    assert_eq!(free_frames(), free);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
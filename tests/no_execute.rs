@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, sync::atomic::{AtomicU64, Ordering}};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, exit_qemu, hlt_loop, memory, serial_print, serial_println, QemuExitCode};
+use x86_64::{registers::control::Cr2, VirtAddr};
+
+// the address the test jumps to, 0 until right before the jump
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    check_kernel_image();
+    jump_through_physical_memory();
+
+    serial_println!("heap memory was executed through the physical memory mapping");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+fn code_marker() {}
+
+fn check_kernel_image() {
+    serial_print!("kernel image is W^X ... ");
+    let code = VirtAddr::new(code_marker as fn() as usize as u64);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, code)).unwrap();
+    assert!(translation.is_executable() && !translation.is_writable());
+
+    let data = VirtAddr::from_ptr(&TARGET);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, data)).unwrap();
+    assert!(translation.is_writable() && !translation.is_executable());
+
+    // still the stack the bootloader set up
+    let local = 0u64;
+    let stack = VirtAddr::from_ptr(&local);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, stack)).unwrap();
+    assert!(translation.is_writable() && !translation.is_executable());
+    serial_println!("\x1b[42m[OK]\x1b[0m");
+}
+
+// the heap itself is checked by `no_execute_heap`, this goes through the
+// alias every frame has in the mapping of the complete physical memory
+fn jump_through_physical_memory() {
+    serial_print!("jumping into heap memory through the physical memory mapping ... ");
+    // a single `ret`, which would return right away if the memory were executable
+    let code = Box::new([0xc3u8; 16]);
+    let addr = memory::with_kernel_memory(|m| {
+        let heap = memory::translate(&mut m.mapper, VirtAddr::from_ptr(code.as_ptr())).unwrap();
+        m.mapper.phys_offset() + heap.phys_addr.as_u64()
+    });
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).unwrap();
+    assert!(translation.is_writable() && !translation.is_executable());
+
+    TARGET.store(addr.as_u64(), Ordering::SeqCst);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(addr.as_u64()) };
+    function();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    // the page fault handler panics, with the faulting address in CR2
+    let target = TARGET.load(Ordering::SeqCst);
+    if target != 0 && Cr2::read().as_u64() == target {
+        serial_println!("\x1b[42m[OK]\x1b[0m");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop();
+    }
+    h_os::test_panic_handler(info);
+}
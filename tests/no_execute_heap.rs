@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, sync::atomic::{AtomicU64, Ordering}};
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, exit_qemu, hlt_loop, memory, serial_print, serial_println, QemuExitCode};
+use x86_64::{registers::control::Cr2, VirtAddr};
+
+// the heap address the test jumps to, 0 until right before the jump
+static TARGET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    jump_into_heap();
+
+    serial_println!("heap memory was executed");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+fn jump_into_heap() {
+    serial_print!("jumping into heap memory ... ");
+    // a single `ret`, which would return right away if the heap were executable
+    let code = Box::new([0xc3u8; 16]);
+    let addr = VirtAddr::from_ptr(code.as_ptr());
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).unwrap();
+    assert!(!translation.is_executable());
+
+    TARGET.store(addr.as_u64(), Ordering::SeqCst);
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    // the page fault handler panics, with the faulting address in CR2
+    let target = TARGET.load(Ordering::SeqCst);
+    if target != 0 && Cr2::read().as_u64() == target {
+        serial_println!("\x1b[42m[OK]\x1b[0m");
+        exit_qemu(QemuExitCode::Success);
+        hlt_loop();
+    }
+    h_os::test_panic_handler(info);
+}
@@ -0,0 +1,109 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, allocator, memory::{self, translate::{self, Mapping}}};
+use x86_64::{structures::paging::{mapper::MappedFrame, Translate}, PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization error");
+
+    test_main();
+
+    hlt_loop()
+}
+
+// any function in the kernel text
+fn code_marker() {}
+
+#[test_case]
+fn physical_memory_mapping() {
+    let offset = memory::with_kernel_memory(|m| m.mapper.phys_offset());
+    let addr = offset + 0xb8123u64;
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).unwrap();
+    assert_eq!(translation.phys_addr, PhysAddr::new(0xb8123));
+    assert!(translation.is_writable());
+}
+
+#[test_case]
+fn agrees_with_mapper_translation() {
+    let offset = memory::with_kernel_memory(|m| m.mapper.phys_offset());
+    let heap_value = Box::new(7u64);
+    let addresses = [
+        VirtAddr::from_ptr(&*heap_value),
+        offset + 0x20_1234u64,
+        VirtAddr::new(code_marker as fn() as usize as u64),
+    ];
+
+    memory::with_kernel_memory(|m| {
+        for addr in addresses {
+            let translation = memory::translate(&mut m.mapper, addr).unwrap();
+            assert_eq!(Some(translation.phys_addr), m.mapper.translate_addr(addr));
+        }
+    });
+}
+
+#[test_case]
+fn heap_pages_are_4kib_and_writable() {
+    let heap_value = Box::new(7u64);
+    let addr = VirtAddr::from_ptr(&*heap_value);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).unwrap();
+    assert!(matches!(translation.frame, MappedFrame::Size4KiB(_)));
+    assert!(translation.is_writable());
+    assert!(!translation.is_user_accessible());
+}
+
+#[test_case]
+fn kernel_code_is_not_writable() {
+    let addr = VirtAddr::new(code_marker as fn() as usize as u64);
+    let translation = memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).unwrap();
+    assert!(translation.is_executable());
+    assert!(!translation.is_writable());
+}
+
+#[test_case]
+fn unmapped_address() {
+    let addr = VirtAddr::new(0xdead_beef_0000);
+    assert!(memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).is_none());
+}
+
+#[test_case]
+fn walk_finds_the_heap() {
+    // the heap grows with 2MiB pages as well, so count bytes instead of pages
+    let mut heap_bytes = 0;
+    memory::with_kernel_memory(|m| {
+        translate::for_each_mapping(&mut m.mapper, |mapping: Mapping| {
+            let start = mapping.start.as_u64() as usize;
+            if (allocator::HEAP_START..allocator::HEAP_START + allocator::heap_size()).contains(&start) {
+                heap_bytes += mapping.size as usize;
+            }
+        });
+    });
+    assert_eq!(heap_bytes, allocator::heap_size());
+}
+
+#[test_case]
+fn print_hierarchy() {
+    memory::with_kernel_memory(|m| translate::print_page_tables(&mut m.mapper));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
@@ -0,0 +1,126 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{panic::PanicInfo, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{acpi, allocator, hlt_loop, memory, rtc::{self, DateTime, RtcError}, time::{self, SystemTime, TickSource, UNIX_EPOCH}};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init().expect("no ACPI tables");
+    time::init_clocks();
+
+    test_main();
+
+    hlt_loop()
+}
+
+fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+    DateTime { year, month, day, hour, minute, second }
+}
+
+#[test_case]
+fn reads_a_plausible_date() {
+    let now = rtc::read().unwrap();
+    assert!(now.is_valid(), "{}", now);
+    assert!((2020..2100).contains(&now.year), "{}", now);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day), "{}", now);
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60, "{}", now);
+}
+
+#[test_case]
+fn converts_to_unix_time() {
+    let dates = [
+        (date(1970, 1, 1, 0, 0, 0), 0),
+        (date(2000, 3, 1, 0, 0, 0), 951_868_800),
+        (date(2024, 2, 29, 12, 34, 56), 1_709_210_096),
+        (date(2099, 12, 31, 23, 59, 59), 4_102_444_799),
+    ];
+    for (date, secs) in dates {
+        assert_eq!(date.to_unix_secs(), secs, "{}", date);
+        assert_eq!(DateTime::from_unix_secs(secs), date);
+    }
+}
+
+#[test_case]
+fn garbage_dates_are_invalid() {
+    assert!(date(1970, 1, 1, 0, 0, 0).is_valid());
+    // what a BCD decode of 0xff registers or a dead battery can produce
+    for date in [
+        date(1969, 12, 31, 23, 59, 59),
+        date(2024, 0, 1, 0, 0, 0),
+        date(2024, 13, 1, 0, 0, 0),
+        date(2024, 1, 0, 0, 0, 0),
+        date(2024, 1, 32, 0, 0, 0),
+        date(2024, 1, 1, 24, 0, 0),
+        date(2024, 1, 1, 0, 60, 0),
+        date(2024, 1, 1, 0, 0, 165),
+    ] {
+        assert!(!date.is_valid(), "{}", date);
+    }
+}
+
+#[test_case]
+fn wall_clock_follows_the_rtc() {
+    let rtc_secs = rtc::read().unwrap().to_unix_secs();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    // the RTC only counts whole seconds
+    assert!(now.abs_diff(rtc_secs) <= 1, "{} vs {}", now, rtc_secs);
+    assert!(SystemTime::boot() <= SystemTime::now());
+}
+
+#[test_case]
+fn wall_clock_advances() {
+    let start = SystemTime::now();
+    time::sleep_ms(20);
+    let elapsed = start.elapsed().unwrap();
+    assert!(elapsed >= Duration::from_millis(20), "{:?}", elapsed);
+    assert!(start.duration_since(SystemTime::now()).is_none());
+}
+
+#[test_case]
+fn rejects_unsupported_rates() {
+    assert_eq!(time::use_rtc(1000), Err(RtcError::UnsupportedFrequency(1000)));
+    assert_eq!(time::use_rtc(16384), Err(RtcError::UnsupportedFrequency(16384)));
+    assert_eq!(time::tick_source(), TickSource::Pit);
+}
+
+#[test_case]
+fn rtc_can_drive_the_timer() {
+    time::use_rtc(1024).unwrap();
+    assert_eq!(time::tick_source(), TickSource::Rtc);
+    assert_eq!(time::frequency(), 1024);
+
+    let start_ticks = time::ticks();
+    let start = time::monotonic_now();
+    while time::monotonic_now() - start < Duration::from_millis(200) {
+        core::hint::spin_loop();
+    }
+    let ticks = time::ticks() - start_ticks;
+    // about 205 ticks, QEMU may deliver timer interrupts late
+    assert!((154..=256).contains(&ticks), "{} ticks in 200ms", ticks);
+
+    time::set_frequency(time::DEFAULT_FREQUENCY);
+    assert_eq!(time::tick_source(), TickSource::Pit);
+    let ticks = time::ticks();
+    time::sleep_ms(10);
+    assert!(time::ticks() - ticks >= 9);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
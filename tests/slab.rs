@@ -0,0 +1,184 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, sync::atomic::{AtomicUsize, Ordering}};
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory, allocator, slab::{self, SlabCache}};
+use x86_64::{structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB}, PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization error");
+
+    test_main();
+
+    hlt_loop()
+}
+
+static CONSTRUCTED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+struct Handle {
+    id: u64,
+    buffer: [u8; 100],
+}
+
+impl Handle {
+    fn new() -> Self {
+        CONSTRUCTED.fetch_add(1, Ordering::Relaxed);
+        Handle { id: 0, buffer: [0; 100] }
+    }
+}
+
+static HANDLES: SlabCache<Handle> = SlabCache::new("handle", Handle::new);
+static LARGE: SlabCache<[u64; 1000]> = SlabCache::new("large", || [7; 1000]);
+
+#[test_case]
+fn objects_are_distinct() {
+    let mut handles = Vec::new();
+    for i in 0..200 {
+        let mut handle = HANDLES.alloc().unwrap();
+        handle.id = i;
+        handles.push(handle);
+    }
+    for (i, handle) in handles.iter().enumerate() {
+        assert_eq!(handle.id, i as u64);
+        assert_eq!(handle.buffer, [0; 100]);
+    }
+    assert_eq!(HANDLES.stats().objects_in_use, 200);
+}
+
+#[test_case]
+fn freed_objects_are_reused_without_construction() {
+    // warm up the cache, so that the next allocation does not need a new slab
+    drop(HANDLES.alloc().unwrap());
+    let constructed = CONSTRUCTED.load(Ordering::Relaxed);
+
+    for _ in 0..1000 {
+        let handle = HANDLES.alloc().unwrap();
+        drop(handle);
+    }
+    assert_eq!(CONSTRUCTED.load(Ordering::Relaxed), constructed);
+}
+
+#[test_case]
+fn large_objects_use_multi_frame_slabs() {
+    let a = LARGE.alloc().unwrap();
+    let b = LARGE.alloc().unwrap();
+    assert_eq!(a[999], 7);
+    assert_eq!(b[0], 7);
+
+    let stats = LARGE.stats();
+    assert!(stats.objects_per_slab >= 2);
+    assert!(stats.frames >= 2);
+}
+
+#[test_case]
+fn stats_and_shrinking() {
+    let before = HANDLES.stats();
+    let handles: Vec<_> = (0..100).map(|_| HANDLES.alloc().unwrap()).collect();
+    let during = HANDLES.stats();
+    assert_eq!(during.objects_in_use, before.objects_in_use + 100);
+    assert_eq!(during.allocations, before.allocations + 100);
+    drop(handles);
+
+    let after = HANDLES.stats();
+    assert_eq!(after.objects_in_use, before.objects_in_use);
+    assert_eq!(after.frees, before.frees + 100);
+
+    // every slab is unused now, so all of them go back to the frame allocator
+    let free_before = memory::with_kernel_memory(|m| m.frame_allocator.free_frames());
+    let freed = HANDLES.shrink();
+    assert_eq!(freed, after.frames);
+    assert_eq!(HANDLES.stats().slabs, 0);
+    assert_eq!(memory::with_kernel_memory(|m| m.frame_allocator.free_frames()), free_before + freed);
+}
+
+// the constructor looks at its own cache, while a slab is being built
+static SELF_AWARE: SlabCache<usize> = SlabCache::new("self aware", || SELF_AWARE.stats().slabs);
+
+#[test_case]
+fn constructors_may_use_their_cache() {
+    assert_eq!(*SELF_AWARE.alloc().unwrap(), 0);
+}
+
+static UNUSED: SlabCache<u64> = SlabCache::new("unused", || 0);
+
+#[test_case]
+fn alloc_fails_while_kernel_memory_is_locked() {
+    assert!(memory::with_kernel_memory(|_| UNUSED.alloc().is_none()));
+    assert!(UNUSED.alloc().is_some());
+}
+
+// every cached object owns heap memory, which is freed when its slab is reclaimed
+static BOXES: SlabCache<Box<[u8; 64]>> = SlabCache::new("boxes", || Box::new([0; 64]));
+
+// the end of the list of frames taken by `take_all_frames`
+const NO_FRAME: u64 = u64::MAX;
+
+/// Take every free frame, linking them through their first word.
+fn take_all_frames() -> u64 {
+    memory::with_kernel_memory(|m| {
+        let offset = m.mapper.phys_offset();
+        let mut head = NO_FRAME;
+        while let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(&mut m.frame_allocator) {
+            unsafe { (offset + frame.start_address().as_u64()).as_mut_ptr::<u64>().write(head) };
+            head = frame.start_address().as_u64();
+        }
+        head
+    })
+}
+
+fn give_back_frames(mut head: u64) {
+    memory::with_kernel_memory(|m| {
+        let offset = m.mapper.phys_offset();
+        while head != NO_FRAME {
+            let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(head));
+            head = unsafe { (offset + head).as_ptr::<u64>().read() };
+            unsafe { m.frame_allocator.deallocate_frame(frame) };
+        }
+    })
+}
+
+#[test_case]
+fn heap_growth_reclaims_caches_that_own_heap_memory() {
+    drop(BOXES.alloc().unwrap());
+    assert!(BOXES.stats().slabs > 0);
+
+    // without free frames, growing the heap has to shrink the caches, which
+    // frees the boxes back into the heap
+    let frames = take_all_frames();
+    let size = allocator::stats().largest_free_block + 4096;
+    let result = allocator::try_vec_with_capacity::<u8>(size);
+    let slabs = BOXES.stats().slabs;
+    give_back_frames(frames);
+    drop(result);
+    assert_eq!(slabs, 0);
+}
+
+#[test_case]
+fn caches_are_registered() {
+    let mut names = Vec::new();
+    slab::for_each_cache(|stats| names.push(stats.name));
+    assert!(names.contains(&"handle"));
+    assert!(names.contains(&"large"));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
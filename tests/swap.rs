@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use h_os::{allocator, block::{BlockDevice, BlockError, RamDisk, BLOCK_SIZE}, hlt_loop, memory::{self, swap, vmm::{self, Region}}};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+// room for 32 pages
+const SWAP_BLOCKS: usize = 32 * 8;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+    swap::enable(Box::new(RamDisk::new(SWAP_BLOCKS))).expect("enabling swap failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, addr)).is_some()
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|m| m.frame_allocator.free_frames())
+}
+
+fn page(range: &vmm::VirtRange, index: u64) -> *mut u64 {
+    (range.start + index * 4096).as_mut_ptr()
+}
+
+#[test_case]
+fn swapped_pages_are_read_back() {
+    let range = vmm::reserve(Region::Mmio, 8 * 4096).unwrap();
+    assert!(vmm::map_swappable(&range, PageTableFlags::WRITABLE));
+    for i in 0..8 {
+        unsafe { page(&range, i).add(i as usize).write_volatile(0x5a5a_0000 + i) };
+    }
+    let free = free_frames();
+
+    // every page was just used, so the first round only clears the accessed bits
+    assert_eq!(swap::reclaim(8), 8);
+    assert_eq!(free_frames(), free + 8);
+    assert_eq!(swap::stats().used_slots, 8);
+    assert!(!is_mapped(range.start));
+
+    for i in 0..8 {
+        assert_eq!(unsafe { page(&range, i).add(i as usize).read_volatile() }, 0x5a5a_0000 + i);
+    }
+    assert_eq!(swap::stats().used_slots, 0);
+    assert_eq!(free_frames(), free);
+    assert!(is_mapped(range.start));
+
+    vmm::unmap_range(&range);
+    vmm::release(range);
+}
+
+#[test_case]
+fn recently_used_pages_stay() {
+    let range = vmm::reserve(Region::Mmio, 2 * 4096).unwrap();
+    assert!(vmm::map_swappable(&range, PageTableFlags::WRITABLE));
+    unsafe {
+        page(&range, 0).write_volatile(1);
+        page(&range, 1).write_volatile(2);
+    }
+
+    assert_eq!(swap::reclaim(1), 1);
+    assert!(!is_mapped(range.start));
+    // bring the first page back, the second one has not been used since the last scan
+    assert_eq!(unsafe { page(&range, 0).read_volatile() }, 1);
+
+    assert_eq!(swap::reclaim(1), 1);
+    assert!(is_mapped(range.start));
+    assert!(!is_mapped(range.start + 4096u64));
+    assert_eq!(unsafe { page(&range, 1).read_volatile() }, 2);
+
+    vmm::unmap_range(&range);
+    vmm::release(range);
+}
+
+#[test_case]
+fn unmapping_frees_swap_slots() {
+    let range = vmm::reserve(Region::Mmio, 4 * 4096).unwrap();
+    assert!(vmm::map_swappable(&range, PageTableFlags::WRITABLE));
+    for i in 0..4 {
+        unsafe { page(&range, i).write_volatile(i) };
+    }
+    assert_eq!(swap::reclaim(4), 4);
+    assert_eq!(swap::stats().used_slots, 4);
+
+    vmm::unmap_range(&range);
+    assert_eq!(swap::stats().used_slots, 0);
+    // the swap entries are gone, so the pages can be mapped normally again
+    vmm::map_range(&range, PageTableFlags::WRITABLE).unwrap();
+    vmm::unmap_range(&range);
+    vmm::release(range);
+}
+
+#[test_case]
+fn lazy_pages_are_not_swapped() {
+    let range = vmm::reserve(Region::Mmio, 4096).unwrap();
+    assert!(vmm::map_lazy(&range, PageTableFlags::WRITABLE));
+    unsafe { page(&range, 0).write_volatile(7) };
+
+    assert_eq!(swap::reclaim(1), 0);
+    assert!(is_mapped(range.start));
+
+    vmm::unmap_range(&range);
+    vmm::release(range);
+}
+
+#[test_case]
+fn ram_disk_checks_ranges() {
+    let mut disk = RamDisk::new(4);
+    let mut buf = [0u8; 2 * BLOCK_SIZE];
+    buf[BLOCK_SIZE] = 0xaa;
+    disk.write_blocks(2, &buf).unwrap();
+    assert_eq!(disk.write_blocks(3, &buf), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_blocks(0, &mut buf[..1]), Err(BlockError::OutOfRange));
+
+    let mut block = [0u8; BLOCK_SIZE];
+    disk.read_blocks(3, &mut block).unwrap();
+    assert_eq!(block[0], 0xaa);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
@@ -0,0 +1,79 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{panic::PanicInfo, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{acpi::{self, fadt::PM_TIMER_FREQUENCY}, allocator, hlt_loop, memory, time};
+use x86_64::{instructions::port::Port, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+
+    hlt_loop()
+}
+
+/// Count timer ticks while the ACPI PM timer, an independent clock, advances by `duration`.
+fn ticks_during(duration: Duration) -> u64 {
+    let port = acpi::init().unwrap().fadt.pm_timer_block.unwrap().io_port().unwrap();
+    let mut pm_timer = Port::<u32>::new(port);
+    // the PM timer has at least 24 bits, which wrap after 4.6 seconds
+    let target = duration.as_micros() as u64 * PM_TIMER_FREQUENCY / 1_000_000;
+    let start_ticks = time::ticks();
+    let start = unsafe { pm_timer.read() };
+    while (unsafe { pm_timer.read() }.wrapping_sub(start) & 0xff_ffff) < target as u32 {
+        core::hint::spin_loop();
+    }
+    time::ticks() - start_ticks
+}
+
+fn assert_rate(frequency: u32) {
+    let expected = frequency as u64 / 5;
+    let ticks = ticks_during(Duration::from_millis(200));
+    // QEMU may deliver timer interrupts late, so allow a generous margin
+    assert!(ticks * 4 >= expected * 3 && ticks * 4 <= expected * 5,
+        "{} ticks in 200ms at {} Hz", ticks, frequency);
+}
+
+#[test_case]
+fn ticks_at_the_default_rate() {
+    assert_eq!(time::frequency(), time::DEFAULT_FREQUENCY);
+    assert_rate(time::DEFAULT_FREQUENCY);
+}
+
+#[test_case]
+fn frequency_can_be_changed() {
+    let before = time::uptime();
+    time::set_frequency(100);
+    assert_eq!(time::frequency(), 100);
+    assert_rate(100);
+    time::set_frequency(time::DEFAULT_FREQUENCY);
+    assert!(time::uptime() > before);
+}
+
+#[test_case]
+fn sleep_waits_long_enough() {
+    let start = time::uptime();
+    let ticks = time::ticks();
+    time::sleep_ms(30);
+    assert!(time::uptime() - start >= Duration::from_millis(30));
+    assert!(time::ticks() - ticks >= 29);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(h_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use h_os::{hlt_loop, memory::{self, vmm::{self, Region}}};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    h_os::init();
+
+    let offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init_kernel_memory(offset, &boot_info.memory_map);
+    }
+
+    test_main();
+
+    hlt_loop()
+}
+
+#[test_case]
+fn reservations_do_not_overlap() {
+    let a = vmm::reserve(Region::Mmio, 3 * 4096).unwrap();
+    let b = vmm::reserve(Region::Mmio, 1).unwrap();
+    assert!(a.end() <= b.start || b.end() <= a.start);
+    assert_eq!(b.size, 4096);
+    assert!(Region::Mmio.contains(a.start) && Region::Mmio.contains(b.start));
+
+    assert!(vmm::reserve_at(Region::Mmio, a.start + 4096u64, 4096).is_none());
+
+    vmm::release(a);
+    vmm::release(b);
+}
+
+#[test_case]
+fn released_ranges_are_reused() {
+    let a = vmm::reserve(Region::PerCpu, 4096).unwrap();
+    vmm::release(a);
+    assert_eq!(vmm::reserve(Region::PerCpu, 4096), Some(a));
+    vmm::release(a);
+}
+
+#[test_case]
+fn aligned_reservation() {
+    let _ = vmm::reserve(Region::Stacks, 4096).unwrap();
+    let aligned = vmm::reserve_aligned(Region::Stacks, 4096, 2 * 1024 * 1024).unwrap();
+    assert!(aligned.start.is_aligned(2u64 * 1024 * 1024));
+    vmm::release(aligned);
+}
+
+#[test_case]
+fn out_of_region_reservation_fails() {
+    assert!(vmm::reserve(Region::PerCpu, Region::PerCpu.size() + 1).is_none());
+    assert!(vmm::reserve_at(Region::PerCpu, Region::Mmio.start(), 4096).is_none());
+}
+
+#[test_case]
+fn map_and_unmap_range() {
+    let range = vmm::reserve(Region::Mmio, 4 * 4096).unwrap();
+    let flags = PageTableFlags::WRITABLE;
+
+    // map once so that the page tables for the range exist
+    vmm::map_range(&range, flags).unwrap();
+    vmm::unmap_range(&range);
+    let free_frames = memory::with_kernel_memory(|m| m.frame_allocator.free_frames());
+
+    vmm::map_range(&range, flags).unwrap();
+    let ptr = range.start.as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert_eq!(memory::with_kernel_memory(|m| m.frame_allocator.free_frames()), free_frames - 4);
+
+    vmm::unmap_range(&range);
+    assert_eq!(memory::with_kernel_memory(|m| m.frame_allocator.free_frames()), free_frames);
+    assert!(memory::with_kernel_memory(|m| memory::translate(&mut m.mapper, range.start)).is_none());
+
+    vmm::release(range);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !{
+    h_os::test_panic_handler(info)
+}